@@ -0,0 +1,223 @@
+use git2::{Commit, Oid, Repository};
+use std::path::Path;
+use submodules;
+use summary::UpdateKind;
+use {classify_update, gitlink_at, parent_submodule_states};
+
+// A main repo commit that changed the gitlink. The rewrite would merge submodule's history at
+// exactly these commits.
+pub struct Bump {
+    pub commit: Oid,
+    pub summary: String,
+    pub old: Option<Oid>,
+    pub new: Oid,
+    // None if submodule's commits aren't available, so we can't tell
+    pub kind: Option<UpdateKind>,
+    pub introduced: Option<usize>,
+}
+
+// Walks the history reachable from all refs and finds the commits that updated the submodule, in
+// topological order
+pub fn find_bumps(repo: &Repository, submodule_dir: &str) -> Vec<Bump> {
+    let submodule_path = Path::new(submodule_dir);
+
+    let mut result = Vec::new();
+    for oid in submodules::history_revwalk(repo).filter_map(Result::ok) {
+        let commit = repo.find_commit(oid)
+            .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
+        let tree = commit.tree()
+            .unwrap_or_else(|_| panic!("Couldn't obtain the tree of a commit with ID {}", oid));
+        let gitlink = gitlink_at(&tree, submodule_path);
+        let gitlink = match gitlink {
+            Some(gitlink) => gitlink,
+            None => continue,
+        };
+
+        // The same rule the rewrite uses: the submodule was updated if its state differs from
+        // the states in all the parents
+        let (parent_states, first_parent_state) = parent_submodule_states(&commit,
+                                                                          submodule_path);
+        if parent_states.contains(&gitlink) {
+            continue;
+        }
+
+        let available = |id: &Oid| repo.find_commit(*id).is_ok();
+        let known = available(&gitlink) && first_parent_state.iter().all(available);
+        result.push(Bump {
+            commit: oid,
+            summary: summary_of(&commit),
+            old: first_parent_state,
+            new: gitlink,
+            kind: if known {
+                Some(classify_update(repo, first_parent_state, gitlink))
+            } else {
+                None
+            },
+            introduced: if known {
+                Some(count_introduced(repo, first_parent_state, gitlink))
+            } else {
+                None
+            },
+        });
+    }
+
+    result
+}
+
+pub fn print_text(repo: &Repository, bumps: &[Bump]) {
+    let short = |id: Oid| {
+        repo.find_object(id, None)
+            .and_then(|object| object.short_id())
+            .map(|short_id| String::from_utf8_lossy(&short_id).into_owned())
+            .unwrap_or_else(|_| id.to_string()[..7].to_string())
+    };
+
+    for bump in bumps {
+        let range = match bump.old {
+            Some(old) => format!("{} -> {}", short(old), short(bump.new)),
+            None => format!("none -> {}", short(bump.new)),
+        };
+        let details = match (bump.kind, bump.introduced) {
+            (Some(kind), Some(introduced)) => {
+                format!("{}, {} new commit{}",
+                        describe_kind(kind),
+                        introduced,
+                        if introduced == 1 { "" } else { "s" })
+            }
+            _ => String::from("submodule's commits aren't available"),
+        };
+        println!("{} {}\n    {} ({})", short(bump.commit), bump.summary, range, details);
+    }
+}
+
+pub fn print_json(bumps: &[Bump]) {
+    print!("{}", to_json(bumps));
+}
+
+// An array with one object per line, so that the output is easy to read and to grep
+fn to_json(bumps: &[Bump]) -> String {
+    let mut result = String::from("[\n");
+    for (i, bump) in bumps.iter().enumerate() {
+        let old = bump.old.map_or(String::from("null"), |id| format!("\"{}\"", id));
+        let kind = bump.kind.map_or(String::from("null"), |kind| format!("\"{}\"", kind_id(kind)));
+        let introduced = bump.introduced.map_or(String::from("null"), |n| n.to_string());
+        result.push_str(&format!("  {{\"commit\": \"{}\", \"summary\": {}, \"old\": {}, \"new\": \
+                                  \"{}\", \"kind\": {}, \"introduced\": {}}}{}\n",
+                                 bump.commit,
+                                 json_string(&bump.summary),
+                                 old,
+                                 bump.new,
+                                 kind,
+                                 introduced,
+                                 if i + 1 == bumps.len() { "" } else { "," }));
+    }
+    result.push_str("]\n");
+    result
+}
+
+// Counts submodule's commits that are reachable from the new state but not from the old one,
+// i.e. the ones that the update brought in
+fn count_introduced(repo: &Repository, old: Option<Oid>, new: Oid) -> usize {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.push(new)
+        .unwrap_or_else(|_| panic!("Couldn't push submodule commit {} onto the RevWalk", new));
+    if let Some(old) = old {
+        revwalk.hide(old)
+            .unwrap_or_else(|_| panic!("Couldn't hide submodule commit {} from the RevWalk", old));
+    }
+    revwalk.count()
+}
+
+fn summary_of(commit: &Commit) -> String {
+    String::from_utf8_lossy(commit.summary_bytes().unwrap_or(b"")).into_owned()
+}
+
+fn describe_kind(kind: UpdateKind) -> &'static str {
+    match kind {
+        UpdateKind::Added => "added",
+        UpdateKind::FastForward => "fast-forward",
+        UpdateKind::Rollback => "rollback",
+        UpdateKind::NonFastForward => "unrelated jump",
+    }
+}
+
+fn kind_id(kind: UpdateKind) -> &'static str {
+    match kind {
+        UpdateKind::NonFastForward => "unrelated-jump",
+        kind => describe_kind(kind),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).expect("Couldn't create an ID")
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\" C:\\dir"), "\"say \\\"hi\\\" C:\\\\dir\"");
+        assert_eq!(json_string("a\nb\rc\td"), "\"a\\nb\\rc\\td\"");
+        assert_eq!(json_string("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+        assert_eq!(json_string("café ✓"), "\"café ✓\"");
+    }
+
+    #[test]
+    fn json_array_has_one_object_per_line() {
+        assert_eq!(to_json(&[]), "[\n]\n");
+
+        let bumps = [Bump {
+                         commit: id(1),
+                         summary: String::from("add sub"),
+                         old: None,
+                         new: id(2),
+                         kind: Some(UpdateKind::Added),
+                         introduced: Some(2),
+                     },
+                     Bump {
+                         commit: id(3),
+                         summary: String::from("jump"),
+                         old: Some(id(2)),
+                         new: id(4),
+                         kind: None,
+                         introduced: None,
+                     }];
+        let expected = format!("[\n  {{\"commit\": \"{}\", \"summary\": \"add sub\", \
+                                \"old\": null, \"new\": \"{}\", \"kind\": \"added\", \
+                                \"introduced\": 2}},\n  {{\"commit\": \"{}\", \"summary\": \
+                                \"jump\", \"old\": \"{}\", \"new\": \"{}\", \"kind\": null, \
+                                \"introduced\": null}}\n]\n",
+                               id(1),
+                               id(2),
+                               id(3),
+                               id(2),
+                               id(4));
+        assert_eq!(to_json(&bumps), expected);
+    }
+
+    #[test]
+    fn unrelated_jumps_have_a_json_friendly_id() {
+        assert_eq!(kind_id(UpdateKind::NonFastForward), "unrelated-jump");
+        assert_eq!(kind_id(UpdateKind::FastForward), "fast-forward");
+    }
+}
@@ -0,0 +1,275 @@
+use checkout;
+use git2::{ObjectType, Oid, Repository};
+use refs;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use submodules;
+use summary::UpdateKind;
+use worktrees;
+use {are_mappings_valid, are_renames_valid, build_commit_writer, classify_update,
+     does_submodule_exist, find_dangling_references_to_submodule, get_excluded_current_branch,
+     get_repo_revwalk, get_submodule_revwalk, gitlink_at, parent_submodule_states,
+     print_dirty_paths, print_submodule_not_found, record_submodule_history,
+     resolve_submodule_commit, worktree_dir, Mode, Options};
+use {E_CURRENT_BRANCH_EXCLUDED, E_DIRTY_WORKDIR, E_FOUND_DANGLING_REFERENCES, E_INVALID_FILTER,
+     E_INVALID_MAPPINGS, E_SUBMODULE_FETCH_FAILED, E_SUBMODULE_NOT_FOUND, E_SUCCESS, E_UNBORN_HEAD};
+
+// How many examples of a problem to show before giving up
+const MAX_EXAMPLES: usize = 10;
+
+// Goes through everything the rewrite would check, but keeps going after a problem is found, and
+// reports what the rewrite would do. Nothing is written: instead of fetching submodule's history,
+// we read its objects right where they are. Returns the exit code the rewrite would fail with, or
+// E_SUCCESS.
+pub fn check(repo: &Repository, options: &Options) -> i32 {
+    let submodule_dir = &options.submodule_dir;
+    let mut problems = Vec::new();
+
+    if let Err(e) = repo.head() {
+        eprintln!("HEAD doesn't point at a commit: {}", e.message());
+        return E_UNBORN_HEAD;
+    }
+
+    let dirty_paths = checkout::find_dirty_paths(repo, submodule_dir);
+    if dirty_paths.iter().any(|dirty| !(options.autostash && dirty.stashable)) {
+        eprintln!("The working directory is dirty:\n");
+        print_dirty_paths(&dirty_paths, options.autostash);
+        eprintln!();
+        problems.push(E_DIRTY_WORKDIR);
+    }
+
+    let worktrees = worktrees::open_linked(repo);
+    for worktree in &worktrees {
+        let dirty_paths = checkout::find_dirty_paths(&worktree.repo, submodule_dir);
+        if !dirty_paths.is_empty() {
+            eprintln!("The working directory of worktree `{}' ({}) is dirty:\n",
+                      worktree.name,
+                      worktree_dir(&worktree.repo).display());
+            print_dirty_paths(&dirty_paths, false);
+            eprintln!();
+            problems.push(E_DIRTY_WORKDIR);
+        }
+    }
+
+    if !does_submodule_exist(repo, submodule_dir) {
+        print_submodule_not_found(submodule_dir);
+        problems.push(E_SUBMODULE_NOT_FOUND);
+        return conclude(&problems);
+    }
+
+    if let Some(branch) = get_excluded_current_branch(repo, &options.refs) {
+        eprintln!("The current branch `{}' is excluded by --refs or --exclude-refs\n", branch);
+        problems.push(E_CURRENT_BRANCH_EXCLUDED);
+    }
+    for worktree in &worktrees {
+        if let Some(branch) = get_excluded_current_branch(&worktree.repo, &options.refs) {
+            eprintln!("The branch `{}', checked out in worktree `{}', is excluded by --refs or \
+                       --exclude-refs\n",
+                      branch,
+                      worktree.name);
+            problems.push(E_CURRENT_BRANCH_EXCLUDED);
+        }
+    }
+
+    // Mailmap and committer are checked the same way the rewrite does it. Signing configuration is
+    // only read: setting up the signer would write the key into the repo and run the signer.
+    if let Err(exit_code) = build_commit_writer(repo, options, true) {
+        eprintln!();
+        problems.push(exit_code);
+    }
+
+    if submodules::borrow_objects(repo, submodule_dir, options.submodule_repo.as_deref()).is_err() {
+        eprintln!("Couldn't read submodule's history! Have you forgot to run `git submodule \
+                   update --recursive`? If the submodule was removed, point at its repository \
+                   with --submodule-repo.");
+        problems.push(E_SUBMODULE_FETCH_FAILED);
+        return conclude(&problems);
+    }
+
+    let mappings_valid =
+        are_mappings_valid(repo, submodule_dir, &options.mappings, &options.default_mapping);
+    if !mappings_valid {
+        eprintln!();
+        problems.push(E_INVALID_MAPPINGS);
+    }
+
+    if !are_renames_valid(repo, submodule_dir, &options.filter) {
+        eprintln!();
+        problems.push(E_INVALID_FILTER);
+    }
+
+    let mut old_id_to_new = HashMap::new();
+    record_submodule_history(repo, &mut old_id_to_new, submodule_dir);
+    let ref_targets = refs::read_ref_targets(repo, &options.refs);
+    let has_dangling_references = find_dangling_references_to_submodule(repo,
+                                                                        submodule_dir,
+                                                                        &old_id_to_new,
+                                                                        &ref_targets,
+                                                                        &options.mappings,
+                                                                        &options.default_mapping)
+        .is_some();
+    if has_dangling_references {
+        eprintln!();
+        problems.push(E_FOUND_DANGLING_REFERENCES);
+    }
+
+    // Until mappings and dangling references are sorted out, we can't tell which submodule
+    // commits the main repo will end up with
+    let updates_known = mappings_valid && !has_dangling_references;
+    print_history_report(repo, options, &ref_targets, &old_id_to_new, updates_known);
+
+    conclude(&problems)
+}
+
+fn conclude(problems: &[i32]) -> i32 {
+    if problems.is_empty() {
+        println!("\nNo problems found; the rewrite should go through.");
+        E_SUCCESS
+    } else {
+        eprintln!("\nFound {} problem(s) that would stop the rewrite.", problems.len());
+        problems[0]
+    }
+}
+
+fn print_history_report(repo: &Repository,
+                        options: &Options,
+                        ref_targets: &HashMap<String, Oid>,
+                        old_id_to_new: &HashMap<Oid, Oid>,
+                        updates_known: bool) {
+    let submodule_path = Path::new(&options.submodule_dir);
+
+    let mut main_commits = 0;
+    let mut main_with_submodule = 0;
+    // Commits made after the submodule was removed descend from rewritten ones, so they'll be
+    // re-created too
+    let mut rewritten = HashSet::new();
+    let mut non_utf8_messages = 0;
+    let mut updates: HashMap<UpdateKind, usize> = HashMap::new();
+    for oid in get_repo_revwalk(repo, ref_targets).filter_map(Result::ok) {
+        let commit = repo.find_commit(oid)
+            .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
+        main_commits += 1;
+
+        let tree = commit.tree()
+            .unwrap_or_else(|_| panic!("Couldn't obtain the tree of a commit with ID {}", oid));
+        let gitlink = gitlink_at(&tree, submodule_path);
+        let gitlink = match gitlink {
+            Some(gitlink) => gitlink,
+            None => {
+                if commit.parent_ids().any(|id| rewritten.contains(&id)) {
+                    rewritten.insert(oid);
+                }
+                continue;
+            }
+        };
+
+        rewritten.insert(oid);
+        main_with_submodule += 1;
+        if std::str::from_utf8(commit.message_raw_bytes()).is_err() {
+            non_utf8_messages += 1;
+        }
+
+        let (parent_states, first_parent_state) = parent_submodule_states(&commit,
+                                                                          submodule_path);
+        if !parent_states.contains(&gitlink) && updates_known {
+            let new = resolve_submodule_commit(&gitlink, old_id_to_new, options);
+            let old = first_parent_state
+                .map(|id| resolve_submodule_commit(&id, old_id_to_new, options));
+            *updates.entry(classify_update(repo, old, new)).or_insert(0) += 1;
+        }
+    }
+
+    let submodule_commits: Vec<Oid> = get_submodule_revwalk(repo, &options.submodule_dir)
+        .filter_map(Result::ok)
+        .collect();
+    let mut non_utf8_submodule_messages = 0;
+    let mut tree_report = TreeReport::default();
+    for oid in &submodule_commits {
+        let commit = repo.find_commit(*oid)
+            .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
+        if std::str::from_utf8(commit.message_raw_bytes()).is_err() {
+            non_utf8_submodule_messages += 1;
+        }
+        scan_tree(repo, commit.tree_id(), &[], &mut tree_report);
+    }
+
+    println!("Main repo commits: {} ({} of them contain the submodule; {} will be rewritten)",
+             main_commits,
+             main_with_submodule,
+             rewritten.len());
+    println!("Submodule commits: {} ({})",
+             submodule_commits.len(),
+             match options.mode {
+                 Mode::Merge => "they will be rewritten",
+                 Mode::Flatten => "they won't be imported",
+                 Mode::Subtree => "they will be merged in as they are",
+             });
+    if !updates_known {
+        println!("Submodule updates: unknown until the problems with mappings or dangling \
+                  references are sorted out");
+    } else {
+        let count = |kind, noun| {
+            let n = updates.get(&kind).cloned().unwrap_or(0);
+            format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+        };
+        println!("Submodule updates: {} ({}, {}, {}, {})",
+                 updates.values().sum::<usize>(),
+                 count(UpdateKind::Added, "addition"),
+                 count(UpdateKind::FastForward, "fast-forward"),
+                 count(UpdateKind::Rollback, "rollback"),
+                 count(UpdateKind::NonFastForward, "unrelated jump"));
+    }
+    println!("Commits with messages that aren't valid UTF-8: {} in the main repo, {} in the \
+              submodule (they'll be kept byte for byte)",
+             non_utf8_messages,
+             non_utf8_submodule_messages);
+
+    print_examples("Submodule's files with names that aren't valid UTF-8 (they'll be kept byte \
+                    for byte)",
+                   &tree_report.non_utf8_paths);
+    print_examples("Nested submodules (they'll remain gitlinks, but without a .gitmodules entry)",
+                   &tree_report.gitlinks);
+}
+
+fn print_examples(title: &str, paths: &HashSet<String>) {
+    println!("{}: {}", title, paths.len());
+    let mut paths: Vec<&String> = paths.iter().collect();
+    paths.sort();
+    for path in paths.iter().take(MAX_EXAMPLES) {
+        println!("    {}", path);
+    }
+    if paths.len() > MAX_EXAMPLES {
+        println!("    ...and {} more", paths.len() - MAX_EXAMPLES);
+    }
+}
+
+#[derive(Default)]
+struct TreeReport {
+    // Trees we've already looked into; submodule's commits share most of them
+    seen: HashSet<Oid>,
+    non_utf8_paths: HashSet<String>,
+    gitlinks: HashSet<String>,
+}
+
+fn scan_tree(repo: &Repository, tree_id: Oid, root: &[u8], report: &mut TreeReport) {
+    if !report.seen.insert(tree_id) {
+        return;
+    }
+
+    let tree = repo.find_tree(tree_id)
+        .unwrap_or_else(|_| panic!("Couldn't find tree {}", tree_id));
+    for entry in tree.iter() {
+        let mut path = root.to_vec();
+        path.extend_from_slice(entry.name_bytes());
+
+        if std::str::from_utf8(entry.name_bytes()).is_err() {
+            report.non_utf8_paths.insert(String::from_utf8_lossy(&path).into_owned());
+        }
+        if entry.filemode() == 0o160000 {
+            report.gitlinks.insert(String::from_utf8_lossy(&path).into_owned());
+        } else if entry.kind() == Some(ObjectType::Tree) {
+            path.push(b'/');
+            scan_tree(repo, entry.id(), &path, report);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use filter::bytes_to_path;
+use git2::build::CheckoutBuilder;
+use git2::{IndexEntry, ObjectType, Oid, Repository, Status, StatusOptions, SubmoduleIgnore,
+           SubmoduleStatus, Tree, TreeWalkMode, TreeWalkResult};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+// What the working directory looked like before the rewrite. Once submodule's .git is gone, there
+// is no way to tell which of its files were changed locally, so we have to find out beforehand.
+pub struct WorkdirState {
+    // Files tracked at submodule's HEAD, relative to submodule's root
+    submodule_tracked: Vec<Vec<u8>>,
+    // Files that are modified, staged, deleted or untracked in the submodule, relative to its root
+    submodule_dirty: HashSet<Vec<u8>>,
+    // ID of the .gitmodules blob at main repo's HEAD
+    gitmodules: Option<Oid>,
+}
+
+impl WorkdirState {
+    pub fn read(repo: &Repository, submodule_dir: &str) -> WorkdirState {
+        // Linked worktrees don't get their submodules initialized unless asked to, in which case
+        // submodule's directory is empty and there's nothing to look at
+        let submodule_repo = repo.find_submodule(submodule_dir)
+            .and_then(|submodule| submodule.open())
+            .ok();
+
+        let mut submodule_tracked = Vec::new();
+        let mut submodule_dirty = HashSet::new();
+        if let Some(submodule_repo) = submodule_repo {
+            let submodule_head_tree = submodule_repo.head()
+                .and_then(|head| head.peel_to_tree())
+                .expect("Couldn't obtain the tree of submodule's HEAD");
+            submodule_tracked = list_blobs(&submodule_head_tree);
+
+            let mut statusopts = StatusOptions::new();
+            statusopts.include_untracked(true);
+            statusopts.recurse_untracked_dirs(true);
+            statusopts.include_ignored(false);
+            submodule_dirty = submodule_repo.statuses(Some(&mut statusopts))
+                .expect("Couldn't get statuses from the submodule")
+                .iter()
+                .map(|entry| entry.path_bytes().to_vec())
+                .collect();
+        }
+
+        let gitmodules = repo.head()
+            .and_then(|head| head.peel_to_tree())
+            .expect("Couldn't obtain the tree HEAD points at")
+            .get_path(Path::new(".gitmodules"))
+            .ok()
+            .map(|entry| entry.id());
+
+        WorkdirState {
+            submodule_tracked,
+            submodule_dirty,
+            gitmodules,
+        }
+    }
+}
+
+// A local change that stands in the way of the rewrite
+pub struct DirtyPath {
+    pub path: String,
+    pub reason: String,
+    // Whether `git stash` would save this change. Stash doesn't know anything about submodules'
+    // contents, and can't save unmerged files.
+    pub stashable: bool,
+}
+
+// Lists the changes that would be lost or would get in the way if we updated the index and the
+// working directory. Untracked files are left alone by the checkout, so they don't count; neither
+// does a submodule that is merely checked out at some other commit, since its files are checked
+// out from the rewritten HEAD anyway.
+pub fn find_dirty_paths(repo: &Repository, submodule_dir: &str) -> Vec<DirtyPath> {
+    let mut result = Vec::new();
+
+    for (path, status) in read_statuses(repo) {
+        if repo.find_submodule(&path).is_err() {
+            result.push(DirtyPath {
+                reason: describe_status(status),
+                stashable: !status.contains(Status::CONFLICTED),
+                path,
+            });
+            continue;
+        }
+
+        let submodule_status = repo.submodule_status(&path, SubmoduleIgnore::Unspecified)
+            .unwrap_or_else(|_| panic!("Couldn't get the status of submodule `{}'", path));
+        let mut reasons = Vec::new();
+        if submodule_status.intersects(SubmoduleStatus::INDEX_ADDED |
+                                       SubmoduleStatus::INDEX_DELETED |
+                                       SubmoduleStatus::INDEX_MODIFIED) {
+            reasons.push("submodule change is staged");
+        }
+        if submodule_status.contains(SubmoduleStatus::WD_DELETED) {
+            reasons.push("submodule's directory is missing");
+        }
+        let has_local_changes = submodule_status.intersects(SubmoduleStatus::WD_INDEX_MODIFIED |
+                                                            SubmoduleStatus::WD_WD_MODIFIED);
+        if has_local_changes && path == submodule_dir {
+            // These are the files we're going to check out, so let's be specific
+            let submodule_repo = repo.find_submodule(&path)
+                .and_then(|submodule| submodule.open())
+                .expect("Couldn't open submodule's repository");
+            for (inner_path, inner_status) in read_statuses(&submodule_repo) {
+                result.push(DirtyPath {
+                    path: format!("{}/{}", path, inner_path),
+                    reason: describe_status(inner_status) + " inside the submodule",
+                    stashable: false,
+                });
+            }
+        } else if has_local_changes {
+            reasons.push("submodule has local changes");
+        }
+
+        if !reasons.is_empty() {
+            result.push(DirtyPath {
+                path,
+                reason: reasons.join(", "),
+                stashable: false,
+            });
+        }
+    }
+
+    result
+}
+
+fn read_statuses(repo: &Repository) -> Vec<(String, Status)> {
+    let mut statusopts = StatusOptions::new();
+    statusopts.include_untracked(false);
+    statusopts.include_ignored(false);
+    statusopts.exclude_submodules(false);
+    repo.statuses(Some(&mut statusopts))
+        .expect("Couldn't get statuses from the repo")
+        .iter()
+        .map(|entry| (String::from_utf8_lossy(entry.path_bytes()).into_owned(), entry.status()))
+        .collect()
+}
+
+fn describe_status(status: Status) -> String {
+    let descriptions = [(Status::CONFLICTED, "unmerged"),
+                        (Status::INDEX_NEW, "added to the index"),
+                        (Status::INDEX_MODIFIED, "changes staged"),
+                        (Status::INDEX_DELETED, "deletion staged"),
+                        (Status::INDEX_RENAMED, "rename staged"),
+                        (Status::INDEX_TYPECHANGE, "type change staged"),
+                        (Status::WT_MODIFIED, "modified"),
+                        (Status::WT_DELETED, "deleted"),
+                        (Status::WT_RENAMED, "renamed"),
+                        (Status::WT_TYPECHANGE, "type changed")];
+    let reasons: Vec<&str> = descriptions.iter()
+        .filter(|&&(flag, _)| status.contains(flag))
+        .map(|&(_, description)| description)
+        .collect();
+    reasons.join(", ")
+}
+
+// Brings submodule's files, which are now under `prefix`, in line with the rewritten HEAD, and
+// removes .gitmodules. That matters if the submodule wasn't checked out at the commit the main
+// repo recorded, or if some of its files were filtered out or renamed. The index is expected to
+// match HEAD already.
+// Files that would be overwritten or removed, but have local changes, are left alone; their paths
+// are returned.
+pub fn sync_submodule_dir(repo: &Repository, state: &WorkdirState, prefix: &str) -> Vec<String> {
+    let workdir = repo.workdir().expect("Repository doesn't have a working directory").to_owned();
+    let head_tree = repo.head()
+        .and_then(|head| head.peel_to_tree())
+        .expect("Couldn't obtain the tree of the rewritten HEAD");
+
+    let prefixed = |path: &[u8]| {
+        let mut result = prefix.as_bytes().to_vec();
+        result.push(b'/');
+        result.extend_from_slice(path);
+        result
+    };
+    let dirty: HashSet<Vec<u8>> = state.submodule_dirty.iter().map(|p| prefixed(p)).collect();
+
+    let mut conflicts = Vec::new();
+
+    let mut wanted = HashSet::new();
+    let mut to_checkout = Vec::new();
+    let subtree = head_tree.get_path(Path::new(prefix))
+        .ok()
+        .and_then(|entry| repo.find_tree(entry.id()).ok());
+    if let Some(subtree) = subtree {
+        subtree.walk(TreeWalkMode::PreOrder, |root, entry| {
+                if entry.kind() != Some(ObjectType::Blob) {
+                    return TreeWalkResult::Ok;
+                }
+
+                let mut path = root.as_bytes().to_vec();
+                path.extend_from_slice(entry.name_bytes());
+                let path = prefixed(&path);
+
+                let actual_id = workdir_blob_id(&workdir.join(bytes_to_path(&path)),
+                                                entry.filemode());
+                if actual_id != Some(entry.id()) {
+                    if dirty.contains(&path) {
+                        conflicts.push(path.clone());
+                    } else {
+                        to_checkout.push(path.clone());
+                    }
+                }
+
+                wanted.insert(path);
+                TreeWalkResult::Ok
+            })
+            .expect("Couldn't walk the tree of the rewritten HEAD");
+    }
+
+    for path in &state.submodule_tracked {
+        let path = prefixed(path);
+        if wanted.contains(&path) {
+            continue;
+        }
+
+        if dirty.contains(&path) {
+            conflicts.push(path);
+        } else {
+            remove_file(&workdir, &path);
+        }
+    }
+
+    if !to_checkout.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().disable_pathspec_match(true);
+        for path in &to_checkout {
+            checkout.path(bytes_to_path(path).into_owned());
+        }
+        repo.checkout_head(Some(&mut checkout))
+            .expect("Couldn't check out submodule's files");
+    }
+
+    // If HEAD doesn't contain the submodule anymore, it's not rewritten, and .gitmodules is left
+    // for the other submodules it might describe
+    let gitmodules_path = workdir.join(".gitmodules");
+    let gitmodules_wanted = head_tree.get_path(Path::new(".gitmodules")).is_ok();
+    if !gitmodules_wanted && gitmodules_path.symlink_metadata().is_ok() {
+        if workdir_blob_id(&gitmodules_path, 0o100644) == state.gitmodules {
+            fs::remove_file(&gitmodules_path).expect("Couldn't remove .gitmodules");
+        } else {
+            conflicts.push(b".gitmodules".to_vec());
+        }
+    }
+
+    conflicts.iter().map(|path| String::from_utf8_lossy(path).into_owned()).collect()
+}
+
+// Fills in the stat data of the index entries whose files match them, like `git update-index
+// --refresh` does. An index read from a tree has none, so Git considers every file modified until
+// something refreshes the index: porcelain like `git status` does it, but plumbing like `git
+// diff-files` (and scripts built on it, like `git subtree`) doesn't. Files with local changes keep
+// their entries as they are.
+pub fn refresh_index(repo: &Repository) {
+    let mut index = repo.index().expect("Couldn't obtain repo's index");
+    index.read(false).expect("Couldn't read the index");
+
+    let entries: Vec<IndexEntry> = index.iter()
+        .filter(|entry| entry.mode != 0o160000)
+        .collect();
+    for entry in entries {
+        let path = bytes_to_path(&entry.path).into_owned();
+        // Re-adding the file hashes it the way Git would, filters and all, and records its stat
+        // data. If it turns out to be different from the entry, the entry is put back.
+        let refreshed = index.add_path(&path).is_ok() &&
+                        index.get_path(&path, 0)
+            .is_some_and(|new_entry| new_entry.id == entry.id && new_entry.mode == entry.mode);
+        if !refreshed {
+            index.add(&entry).expect("Couldn't restore an index entry");
+        }
+    }
+
+    index.write().expect("Couldn't write the index back to the repo");
+}
+
+fn list_blobs(tree: &Tree) -> Vec<Vec<u8>> {
+    let mut result = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                let mut path = root.as_bytes().to_vec();
+                path.extend_from_slice(entry.name_bytes());
+                result.push(path);
+            }
+            TreeWalkResult::Ok
+        })
+        .expect("Couldn't walk submodule's tree");
+    result
+}
+
+// Returns the ID the file would have if it were added to Git, or None if it doesn't exist
+fn workdir_blob_id(path: &Path, filemode: i32) -> Option<Oid> {
+    let metadata = path.symlink_metadata().ok()?;
+    if filemode == 0o120000 {
+        if !metadata.file_type().is_symlink() {
+            return None;
+        }
+        let target = fs::read_link(path).ok()?;
+        Oid::hash_object(ObjectType::Blob, target.to_string_lossy().as_bytes()).ok()
+    } else {
+        if !metadata.is_file() {
+            return None;
+        }
+        Oid::hash_file(ObjectType::Blob, path).ok()
+    }
+}
+
+// Removes a file, along with the directories that became empty because of that
+fn remove_file(workdir: &Path, path: &[u8]) {
+    let full_path = workdir.join(bytes_to_path(path));
+    if fs::remove_file(&full_path).is_err() {
+        return;
+    }
+
+    let mut dir = full_path.parent();
+    while let Some(d) = dir {
+        if d == workdir || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
@@ -0,0 +1,231 @@
+use filter::PathFilter;
+use git2::Oid;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+
+// Everything `verify` needs to know about a rewrite in order to check it without repeating it.
+// It's stored as text, one entry per line, so that other tools can use it as well:
+//
+//     submodule-dir <path>
+//     prefix <path>
+//     changed <authors|committers|messages|trailers>
+//     include <glob>
+//     exclude <glob>
+//     rename-from <directory>
+//     rename-to <directory, or nothing for the root>
+//     submodule <original submodule commit> <rewritten submodule commit>
+//     gitlink <submodule commit recorded in the main repo> <submodule commit used instead>
+//     subtree <submodule commit used> <tree put into the prefix directory, or zeroes if none>
+//     commit <original commit> <rewritten commit>
+//     split <original commit> <rewritten commit without the submodule update>
+//
+// `changed` lines list the metadata that was deliberately changed by options like --use-mailmap
+// or --rewrite-messages: `messages` means that commit IDs in messages were replaced with the
+// rewritten ones, and `trailers` that messages got trailers like Submerged-from appended to them.
+// `include`, `exclude` and `rename-*` lines record the filters applied to the submodule's files;
+// every `rename-from` is followed by its `rename-to`. `split` lines are only there for commits
+// rewritten with --split-updates: the `commit` line points at the merge, and the `split` one at
+// the commit with the rest of changes.
+pub struct CommitMap {
+    pub submodule_dir: String,
+    pub prefix: String,
+    pub changed: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub renames: Vec<(String, String)>,
+    pub submodule: Vec<(Oid, Oid)>,
+    pub gitlinks: BTreeMap<Oid, Oid>,
+    pub subtrees: BTreeMap<Oid, Option<Oid>>,
+    pub commits: Vec<(Oid, Oid)>,
+    pub splits: HashMap<Oid, Oid>,
+}
+
+impl CommitMap {
+    pub fn new(submodule_dir: &str, prefix: &str) -> CommitMap {
+        CommitMap {
+            submodule_dir: String::from(submodule_dir),
+            prefix: String::from(prefix),
+            changed: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            renames: Vec::new(),
+            submodule: Vec::new(),
+            gitlinks: BTreeMap::new(),
+            subtrees: BTreeMap::new(),
+            commits: Vec::new(),
+            splits: HashMap::new(),
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let mut lines = vec![format!("submodule-dir {}", self.submodule_dir),
+                             format!("prefix {}", self.prefix)];
+        lines.extend(self.changed.iter().map(|what| format!("changed {}", what)));
+        lines.extend(self.include.iter().map(|glob| format!("include {}", glob)));
+        lines.extend(self.exclude.iter().map(|glob| format!("exclude {}", glob)));
+        for (from, to) in &self.renames {
+            lines.push(format!("rename-from {}", from));
+            lines.push(format!("rename-to {}", to));
+        }
+        lines.extend(self.submodule.iter().map(|&(old, new)| format!("submodule {} {}", old, new)));
+        lines.extend(self.gitlinks.iter().map(|(old, new)| format!("gitlink {} {}", old, new)));
+        lines.extend(self.subtrees.iter().map(|(commit, tree)| {
+            format!("subtree {} {}", commit, tree.unwrap_or_else(Oid::zero))
+        }));
+        for &(old, new) in &self.commits {
+            lines.push(format!("commit {} {}", old, new));
+            if let Some(split) = self.splits.get(&old) {
+                lines.push(format!("split {} {}", old, split));
+            }
+        }
+
+        let mut file = fs::File::create(path).map_err(|e| e.to_string())?;
+        for line in lines {
+            writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &str) -> Result<CommitMap, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let mut map = CommitMap::new("", "");
+        let mut rename_from = None;
+        for (number, line) in contents.lines().enumerate() {
+            let error = || format!("line {} is malformed: {}", number + 1, line);
+
+            let mut parts = line.splitn(2, ' ');
+            let kind = parts.next().unwrap_or("");
+            let rest = parts.next().ok_or_else(&error)?;
+            if rename_from.is_some() != (kind == "rename-to") {
+                return Err(error());
+            }
+            match kind {
+                "submodule-dir" => map.submodule_dir = String::from(rest),
+                "prefix" => map.prefix = String::from(rest),
+                "changed" => map.changed.push(String::from(rest)),
+                "include" => map.include.push(String::from(rest)),
+                "exclude" => map.exclude.push(String::from(rest)),
+                "rename-from" => rename_from = Some(String::from(rest)),
+                "rename-to" => {
+                    let from = rename_from.take().ok_or_else(&error)?;
+                    map.renames.push((from, String::from(rest)));
+                }
+                _ => {
+                    let ids: Vec<Oid> = rest.split(' ')
+                        .map(Oid::from_str)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| error())?;
+                    if ids.len() != 2 {
+                        return Err(error());
+                    }
+                    let (old, new) = (ids[0], ids[1]);
+                    match kind {
+                        "submodule" => map.submodule.push((old, new)),
+                        "gitlink" => {
+                            map.gitlinks.insert(old, new);
+                        }
+                        "subtree" => {
+                            map.subtrees.insert(old, if new.is_zero() { None } else { Some(new) });
+                        }
+                        "commit" => map.commits.push((old, new)),
+                        "split" => {
+                            map.splits.insert(old, new);
+                        }
+                        _ => return Err(error()),
+                    }
+                }
+            }
+        }
+
+        if rename_from.is_some() {
+            return Err(String::from("the last rename-from has no rename-to"));
+        }
+        if map.submodule_dir.is_empty() || map.prefix.is_empty() {
+            return Err(String::from("submodule-dir or prefix is missing"));
+        }
+        Ok(map)
+    }
+
+    pub fn record_filter(&mut self, filter: &PathFilter) {
+        self.include = filter.include_globs().to_vec();
+        self.exclude = filter.exclude_globs().to_vec();
+        self.renames = filter.renames().to_vec();
+    }
+
+    // Rebuilds the filter the rewrite was done with
+    pub fn filter(&self) -> Result<PathFilter, ::git2::Error> {
+        let include: Vec<&str> = self.include.iter().map(|glob| glob.as_str()).collect();
+        let exclude: Vec<&str> = self.exclude.iter().map(|glob| glob.as_str()).collect();
+        PathFilter::new(&include, &exclude, self.renames.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempRepo;
+
+    fn id(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).expect("Couldn't create an ID")
+    }
+
+    #[test]
+    fn written_map_reads_back_the_same() {
+        let mut map = CommitMap::new("ext/sub module", "lib/sub");
+        map.changed.push(String::from("authors"));
+        map.changed.push(String::from("trailers"));
+        map.include.push(String::from("src/**"));
+        map.exclude.push(String::from("*.md"));
+        map.renames.push((String::from("src"), String::from("")));
+        map.renames.push((String::from("doc"), String::from("docs")));
+        map.submodule.push((id(1), id(2)));
+        map.gitlinks.insert(id(3), id(1));
+        map.subtrees.insert(id(1), Some(id(4)));
+        map.subtrees.insert(id(5), None);
+        map.commits.push((id(6), id(7)));
+        map.commits.push((id(8), id(9)));
+        map.splits.insert(id(8), id(10));
+
+        let temp = TempRepo::new();
+        let path = temp.path.join("submerge.map");
+        let path = path.to_str().expect("Temporary path isn't valid UTF-8");
+        map.write(path).expect("Couldn't write the commit map");
+        let read = CommitMap::read(path).expect("Couldn't read the commit map");
+
+        assert_eq!(read.submodule_dir, map.submodule_dir);
+        assert_eq!(read.prefix, map.prefix);
+        assert_eq!(read.changed, map.changed);
+        assert_eq!(read.include, map.include);
+        assert_eq!(read.exclude, map.exclude);
+        assert_eq!(read.renames, map.renames);
+        assert_eq!(read.submodule, map.submodule);
+        assert_eq!(read.gitlinks, map.gitlinks);
+        assert_eq!(read.subtrees, map.subtrees);
+        assert_eq!(read.commits, map.commits);
+        assert_eq!(read.splits, map.splits);
+    }
+
+    #[test]
+    fn malformed_maps_are_rejected() {
+        let temp = TempRepo::new();
+        let path = temp.path.join("submerge.map");
+        let path = path.to_str().expect("Temporary path isn't valid UTF-8");
+        let full = format!("submodule-dir sub\nprefix sub\ncommit {} {}\n", id(1), id(2));
+        for contents in &[format!("commit {} {}\n", id(1), id(2)),
+                          format!("{}commit {}\n", full, id(1)),
+                          format!("{}commit {} nonsense\n", full, id(1)),
+                          format!("{}unknown {} {}\n", full, id(1), id(2)),
+                          format!("{}prefix\n", full),
+                          format!("{}rename-to src\n", full),
+                          format!("{}rename-from src\n", full),
+                          format!("{}rename-from src\ncommit {} {}\n", full, id(3), id(4))] {
+            fs::write(path, contents).expect("Couldn't write the commit map");
+            assert!(CommitMap::read(path).is_err(), "accepted {:?}", contents);
+        }
+
+        fs::write(path, full).expect("Couldn't write the commit map");
+        assert!(CommitMap::read(path).is_ok());
+    }
+}
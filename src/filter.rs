@@ -0,0 +1,312 @@
+use git2::{Pathspec, PathspecFlags, Repository};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+// Decides which of the submodule's files make it into the main repo, and under what names
+pub struct PathFilter {
+    include: Option<Pathspec>,
+    exclude: Option<Pathspec>,
+    // The globs the pathspecs were made of, so that the filter can be recorded in the commit map
+    include_globs: Vec<String>,
+    exclude_globs: Vec<String>,
+    // Pairs of (old directory, new directory); the new one may be empty, meaning "the root"
+    renames: Vec<(String, String)>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[&str],
+               exclude: &[&str],
+               renames: Vec<(String, String)>)
+               -> Result<PathFilter, ::git2::Error> {
+        let include_globs = include.iter().map(|glob| String::from(*glob)).collect();
+        let exclude_globs = exclude.iter().map(|glob| String::from(*glob)).collect();
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(Pathspec::new(include.iter())?)
+        };
+        let exclude = if exclude.is_empty() {
+            None
+        } else {
+            Some(Pathspec::new(exclude.iter())?)
+        };
+
+        Ok(PathFilter {
+            include,
+            exclude,
+            include_globs,
+            exclude_globs,
+            renames,
+        })
+    }
+
+    pub fn include_globs(&self) -> &[String] {
+        &self.include_globs
+    }
+
+    pub fn exclude_globs(&self) -> &[String] {
+        &self.exclude_globs
+    }
+
+    pub fn renames(&self) -> &[(String, String)] {
+        &self.renames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none() && self.renames.is_empty()
+    }
+
+    // Only renames can put two files at the same path
+    pub fn has_renames(&self) -> bool {
+        !self.renames.is_empty()
+    }
+
+    // Returns the path under which the submodule's file should be stored, or None if the file
+    // should be left out. Globs are matched against the original path; renames are tried in the
+    // order they were given, and the first one that matches wins. Paths are raw bytes, just as Git
+    // stores them, so they don't have to be valid UTF-8.
+    pub fn apply(&self, path: &[u8]) -> Option<Vec<u8>> {
+        if self.include.is_some() || self.exclude.is_some() {
+            let fs_path = bytes_to_path(path);
+
+            if let Some(ref include) = self.include {
+                if !include.matches_path(&fs_path, PathspecFlags::DEFAULT) {
+                    return None;
+                }
+            }
+
+            if let Some(ref exclude) = self.exclude {
+                if exclude.matches_path(&fs_path, PathspecFlags::DEFAULT) {
+                    return None;
+                }
+            }
+        }
+
+        for (from, to) in &self.renames {
+            let from = from.as_bytes();
+            if path.starts_with(from) && path.get(from.len()) == Some(&b'/') {
+                let rest = &path[from.len() + 1..];
+                if to.is_empty() {
+                    return Some(rest.to_vec());
+                } else {
+                    let mut new_path = to.clone().into_bytes();
+                    new_path.push(b'/');
+                    new_path.extend_from_slice(rest);
+                    return Some(new_path);
+                }
+            }
+        }
+
+        Some(path.to_vec())
+    }
+
+    // Finds two of the given paths that the filter puts at the same place, or so that a file ends
+    // up where the other one needs a directory. Either way, one of them would be lost. Returns
+    // both original paths, and the path where they collided.
+    pub fn find_collision<'p, I>(&self, paths: I) -> Option<(Vec<u8>, Vec<u8>, Vec<u8>)>
+        where I: IntoIterator<Item = &'p [u8]>
+    {
+        let mut placed: BTreeMap<Vec<u8>, &[u8]> = BTreeMap::new();
+        for path in paths {
+            let new_path = match self.apply(path) {
+                Some(new_path) => new_path,
+                None => continue,
+            };
+
+            let mut dir = new_path.clone();
+            dir.push(b'/');
+            let parents = new_path.iter().enumerate().filter(|&(_, &b)| b == b'/').map(|(i, _)| i);
+            let taken = parents.map(|end| new_path[..end].to_vec())
+                .chain(Some(new_path.clone()))
+                .find(|p| placed.contains_key(p))
+                .or_else(|| {
+                    placed.range(dir.clone()..)
+                        .next()
+                        .map(|(p, _)| p.clone())
+                        .filter(|p| p.starts_with(&dir))
+                });
+            if let Some(taken) = taken {
+                return Some((placed[&taken].to_vec(), path.to_vec(), taken));
+            }
+
+            placed.insert(new_path, path);
+        }
+        None
+    }
+}
+
+// libgit2 takes paths as bytes anyway, so on Unix we pass them through untouched. Elsewhere, paths
+// have to be valid Unicode, so the best we can do is to replace invalid sequences.
+#[cfg(unix)]
+pub fn bytes_to_path(path: &[u8]) -> Cow<'_, Path> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(Path::new(OsStr::from_bytes(path)))
+}
+
+#[cfg(not(unix))]
+pub fn bytes_to_path(path: &[u8]) -> Cow<'_, Path> {
+    Cow::Owned(String::from_utf8_lossy(path).into_owned().into())
+}
+
+// Decides which refs are walked and moved to the rewritten history
+pub struct RefFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl RefFilter {
+    // Without any globs to include, all refs are selected
+    pub fn new(include: &[&str], exclude: &[&str]) -> RefFilter {
+        let include = if include.is_empty() {
+            vec![String::from("refs/*")]
+        } else {
+            include.iter().flat_map(|glob| expand_ref_glob(glob)).collect()
+        };
+        let exclude = exclude.iter().flat_map(|glob| expand_ref_glob(glob)).collect();
+
+        RefFilter { include, exclude }
+    }
+
+    // Returns full names of the refs that match at least one of the included globs, and none of
+    // the excluded ones
+    pub fn select(&self, repo: &Repository) -> Result<BTreeSet<String>, ::git2::Error> {
+        let mut selected = BTreeSet::new();
+        for glob in &self.include {
+            selected.extend(glob_ref_names(repo, glob)?);
+        }
+        for glob in &self.exclude {
+            for name in glob_ref_names(repo, glob)? {
+                selected.remove(&name);
+            }
+        }
+        Ok(selected)
+    }
+}
+
+// Globs that don't start with "refs/" are tried against branches, tags and remote-tracking
+// branches, so "master" or "release/*" do what one would expect
+fn expand_ref_glob(glob: &str) -> Vec<String> {
+    if glob.starts_with("refs/") {
+        vec![String::from(glob)]
+    } else {
+        ["refs/heads/", "refs/tags/", "refs/remotes/"]
+            .iter()
+            .map(|namespace| String::from(*namespace) + glob)
+            .collect()
+    }
+}
+
+fn glob_ref_names(repo: &Repository, glob: &str) -> Result<Vec<String>, ::git2::Error> {
+    let mut result = Vec::new();
+    for name in repo.references_glob(glob)?.names() {
+        match name {
+            Ok(name) => result.push(String::from(name)),
+            Err(e) => eprintln!("Skipping a ref whose name couldn't be read: {}", e.message()),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempRepo;
+
+    fn renames(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(from, to)| (String::from(from), String::from(to))).collect()
+    }
+
+    fn apply(filter: &PathFilter, path: &str) -> Option<String> {
+        filter.apply(path.as_bytes()).map(|path| String::from_utf8(path).expect("Non-UTF-8 path"))
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = PathFilter::new(&["src", "docs"], &["src/generated"], Vec::new())
+            .expect("Couldn't create a filter");
+        assert_eq!(apply(&filter, "src/main.c"), Some(String::from("src/main.c")));
+        assert_eq!(apply(&filter, "docs/index.md"), Some(String::from("docs/index.md")));
+        assert_eq!(apply(&filter, "src/generated/parser.c"), None);
+        assert_eq!(apply(&filter, "README"), None);
+    }
+
+    #[test]
+    fn globs_are_matched_before_renames() {
+        let filter = PathFilter::new(&[], &["lib/*.bin"], renames(&[("lib", "")]))
+            .expect("Couldn't create a filter");
+        assert_eq!(apply(&filter, "lib/data.bin"), None);
+        assert_eq!(apply(&filter, "lib/code.c"), Some(String::from("code.c")));
+    }
+
+    #[test]
+    fn first_matching_rename_wins() {
+        let filter = PathFilter::new(&[],
+                                     &[],
+                                     renames(&[("lib/core", "core"), ("lib", "vendor")]))
+            .expect("Couldn't create a filter");
+        assert_eq!(apply(&filter, "lib/core/a.c"), Some(String::from("core/a.c")));
+        assert_eq!(apply(&filter, "lib/b.c"), Some(String::from("vendor/b.c")));
+        assert_eq!(apply(&filter, "library/c.c"), Some(String::from("library/c.c")));
+        assert_eq!(apply(&filter, "lib"), Some(String::from("lib")));
+    }
+
+    #[test]
+    fn renames_onto_the_same_path_collide() {
+        let filter = PathFilter::new(&[], &[], renames(&[("a", "common"), ("b", "common")]))
+            .expect("Couldn't create a filter");
+        let paths: [&[u8]; 3] = [b"a/x", b"a/y", b"b/y"];
+        assert_eq!(filter.find_collision(paths.iter().cloned()),
+                   Some((b"a/y".to_vec(), b"b/y".to_vec(), b"common/y".to_vec())));
+
+        let paths: [&[u8]; 2] = [b"a/x", b"b/y"];
+        assert_eq!(filter.find_collision(paths.iter().cloned()), None);
+    }
+
+    #[test]
+    fn files_and_directories_collide() {
+        let filter = PathFilter::new(&[], &[], renames(&[("lib", "")]))
+            .expect("Couldn't create a filter");
+        let paths: [&[u8]; 2] = [b"lib/src/a.c", b"src"];
+        assert_eq!(filter.find_collision(paths.iter().cloned()),
+                   Some((b"lib/src/a.c".to_vec(), b"src".to_vec(), b"src/a.c".to_vec())));
+
+        let paths: [&[u8]; 2] = [b"src", b"lib/src/a.c"];
+        assert_eq!(filter.find_collision(paths.iter().cloned()),
+                   Some((b"src".to_vec(), b"lib/src/a.c".to_vec(), b"src".to_vec())));
+
+        let paths: [&[u8]; 2] = [b"lib/src-old", b"src/a.c"];
+        assert_eq!(filter.find_collision(paths.iter().cloned()), None);
+    }
+
+    #[test]
+    fn short_ref_globs_match_branches_tags_and_remotes() {
+        let temp = TempRepo::new();
+        let commit = temp.commit("commit", &[]);
+        for name in &["refs/heads/master",
+                      "refs/heads/release/1.0",
+                      "refs/heads/release/archive/0.9",
+                      "refs/tags/release/2.0",
+                      "refs/remotes/origin/master",
+                      "refs/pull/1/head"] {
+            temp.repo.reference(name, commit, true, "test").expect("Couldn't create a ref");
+        }
+        let select = |include: &[&str], exclude: &[&str]| {
+            RefFilter::new(include, exclude)
+                .select(&temp.repo)
+                .expect("Couldn't select refs")
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(select(&["master", "origin/master"], &[]),
+                   vec!["refs/heads/master", "refs/remotes/origin/master"]);
+        assert_eq!(select(&["release/*"], &["release/archive/*"]),
+                   vec!["refs/heads/release/1.0", "refs/tags/release/2.0"]);
+        assert_eq!(select(&["refs/pull/*"], &[]), vec!["refs/pull/1/head"]);
+        assert_eq!(select(&[], &["refs/heads/*", "refs/tags/*", "origin/*"]),
+                   vec!["refs/pull/1/head"]);
+    }
+}
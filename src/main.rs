@@ -2,12 +2,33 @@
 extern crate clap;
 extern crate git2;
 
-use git2::{Repository, Commit, Oid, Revwalk, Index, Tree};
+use git2::{Repository, Commit, Mailmap, Oid, Revwalk, Index, Signature, Time, Tree};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-
-#[macro_use]
-mod macros;
+use std::path::{Path, PathBuf};
+
+mod bumps;
+mod check;
+mod checkout;
+mod commitmap;
+mod filter;
+mod message;
+mod objects;
+mod refs;
+mod sign;
+mod submodules;
+mod summary;
+#[cfg(test)]
+mod testutil;
+mod verify;
+mod worktrees;
+
+use checkout::{DirtyPath, WorkdirState};
+use commitmap::CommitMap;
+use filter::{PathFilter, RefFilter};
+use objects::{CommitWriter, NewCommit};
+use refs::RefUpdate;
+use sign::Signer;
+use summary::{Summary, SubmoduleUpdate, UpdateKind};
 
 const E_SUCCESS: i32 = 0;
 const E_NO_GIT_REPO: i32 = 1;
@@ -17,110 +38,816 @@ const E_INVALID_MAPPINGS: i32 = 4;
 const E_DIRTY_WORKDIR: i32 = 5;
 const E_SUBMODULE_FETCH_FAILED: i32 = 6;
 const E_SUBMODULE_NOT_FOUND: i32 = 7;
+const E_INVALID_PREFIX: i32 = 8;
+const E_INVALID_FILTER: i32 = 9;
+const E_SIGNING_NOT_CONFIGURED: i32 = 10;
+const E_INVALID_MAILMAP: i32 = 11;
+const E_INVALID_COMMITTER: i32 = 12;
+const E_CURRENT_BRANCH_EXCLUDED: i32 = 13;
+const E_REF_UPDATE_FAILED: i32 = 14;
+const E_UNBORN_HEAD: i32 = 15;
+const E_CHECKOUT_CONFLICTS: i32 = 16;
+const E_AUTOSTASH_FAILED: i32 = 17;
+const E_COMMIT_MAP_FAILED: i32 = 18;
+const E_VERIFICATION_FAILED: i32 = 19;
 
 fn main() {
     let exit_code = real_main();
     std::process::exit(exit_code);
 }
 
+// How the submodule ends up in the main repo
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    // Submodule's history is rewritten and merged into the repo's history
+    Merge,
+    // Gitlinks are replaced by submodule's trees, but its history isn't imported
+    Flatten,
+    // Submodule's commits are kept intact and merged in the way `git merge -s subtree` does
+    Subtree,
+}
+
+// Where the submodule commit goes among the parents of the merges we create
+#[derive(Clone, Copy, PartialEq)]
+enum ParentOrder {
+    First,
+    Last,
+}
+
+// Who's recorded as the committer of the commits we create
+enum CommitterPolicy {
+    // Whoever committed the original commit
+    Keep,
+    // The user running git-submerge, at the time it started
+    Current,
+    // Given name and email, with the given time or the time git-submerge started
+    Explicit {
+        name: String,
+        email: String,
+        time: Option<Time>,
+    },
+}
+
+struct Options {
+    submodule_dir: String,
+    // Directory where submodule's files will end up; the same as `submodule_dir` by default
+    prefix: String,
+    // Which of the submodule's files are imported, and under what names
+    filter: PathFilter,
+    mappings: HashMap<Oid, Oid>,
+    default_mapping: Option<Oid>,
+    mode: Mode,
+    // Whether commits that updated the submodule and changed other files should be split in two
+    split_updates: bool,
+    parent_order: ParentOrder,
+    // Whether rewritten commits should mention the IDs of the commits they came from
+    annotate: bool,
+    // Whether the merges should mention which submodule commits they bring in
+    annotate_updates: bool,
+    // Whether IDs of rewritten commits should be replaced in commit and tag messages
+    rewrite_messages: bool,
+    // Whether the commits we create should be signed with user's key
+    sign: bool,
+    // Whether the repo's mailmap should be applied to authors and committers
+    use_mailmap: bool,
+    // Mailmap to use instead of the repo's one
+    mailmap_file: Option<String>,
+    committer: CommitterPolicy,
+    // Which refs are walked and moved to the rewritten history
+    refs: RefFilter,
+    // Whether local changes in the main repo should be stashed and re-applied after the rewrite
+    autostash: bool,
+    // Where to save the commit map for `verify`
+    commit_map_file: Option<String>,
+    // Where to fetch submodule's history from, if not from its working copy
+    submodule_repo: Option<String>,
+}
+
+enum Command {
+    Submerge(Box<Options>),
+    // Reports what the rewrite would do, without doing it
+    Check(Box<Options>),
+    // Lists all the submodules the history has ever had
+    List,
+    // Lists the commits that updated the submodule
+    Bumps {
+        submodule_dir: String,
+        submodule_repo: Option<String>,
+        json: bool,
+    },
+    // Checks a rewrite against the commit map it saved
+    Verify { commit_map_file: String },
+}
+
+const AUTOSTASH_MESSAGE: &str = "git-submerge: autostash";
+
 fn real_main() -> i32 {
-    let mut mappings: HashMap<Oid, Oid> = HashMap::new();
-    let (submodule_dir, default_mapping) = match parse_cli_arguments(&mut mappings) {
-        Ok((dir, oid)) => (dir, oid),
+    let options = match parse_cli_arguments() {
+        Ok(Command::Submerge(options)) => *options,
+        Ok(Command::Check(options)) => return check_main(&options),
+        Ok(Command::List) => return list_main(),
+        Ok(Command::Bumps { submodule_dir, submodule_repo, json }) => {
+            return bumps_main(&submodule_dir, submodule_repo.as_deref(), json)
+        }
+        Ok(Command::Verify { commit_map_file }) => return verify_main(&commit_map_file),
         Err(exit_code) => return exit_code,
     };
+    let submodule_dir = &options.submodule_dir;
 
-
-    let repo = match Repository::open(".") {
+    let mut repo = match open_repo() {
         Ok(repo) => repo,
-        Err(e) => {
-            eprintln!("Couldn't find Git repo in the current directory: {}",
-                      e.message());
-            return E_NO_GIT_REPO;
-        }
+        Err(exit_code) => return exit_code,
     };
 
-    if !is_workdir_clean(&repo) {
-        eprintln!("The working directory is dirty, aborting!");
+    if let Err(e) = repo.head() {
+        if e.code() == git2::ErrorCode::UnbornBranch {
+            eprintln!("The current branch doesn't have any commits yet, so there's nothing to \
+                       rewrite");
+        } else {
+            eprintln!("Couldn't figure out what HEAD points at: {}", e.message());
+        }
+        return E_UNBORN_HEAD;
+    }
+
+    let dirty_paths = checkout::find_dirty_paths(&repo, submodule_dir);
+    let autostash = options.autostash && !dirty_paths.is_empty();
+    if dirty_paths.iter().any(|dirty| !(options.autostash && dirty.stashable)) {
+        eprintln!("The working directory is dirty, aborting!\n");
+        print_dirty_paths(&dirty_paths, options.autostash);
+        if !options.autostash && dirty_paths.iter().all(|dirty| dirty.stashable) {
+            eprintln!("\nCommit or stash these changes, or pass --autostash.");
+        }
         return E_DIRTY_WORKDIR;
     }
 
-    if !does_submodule_exist(&repo, &submodule_dir) {
-        eprintln!("Couldn't find a submodule named `{}'", submodule_dir);
+    // Linked worktrees are updated along with the main one, so they have to be clean too
+    let worktrees = worktrees::open_linked(&repo);
+    for worktree in &worktrees {
+        let dirty_paths = checkout::find_dirty_paths(&worktree.repo, submodule_dir);
+        if !dirty_paths.is_empty() {
+            eprintln!("The working directory of worktree `{}' ({}) is dirty, aborting!\n",
+                      worktree.name,
+                      worktree_dir(&worktree.repo).display());
+            print_dirty_paths(&dirty_paths, false);
+            return E_DIRTY_WORKDIR;
+        }
+    }
+
+    if !does_submodule_exist(&repo, submodule_dir) {
+        print_submodule_not_found(submodule_dir);
         return E_SUBMODULE_NOT_FOUND;
     }
 
-    match fetch_submodule_history(&repo, &submodule_dir) {
+    // We're going to update the index and the working directory to match the rewritten history,
+    // so the current branch has to be rewritten as well
+    if let Some(branch) = get_excluded_current_branch(&repo, &options.refs) {
+        eprintln!("The current branch `{}' is excluded by --refs or --exclude-refs; check out \
+                   another branch first",
+                  branch);
+        return E_CURRENT_BRANCH_EXCLUDED;
+    }
+    for worktree in &worktrees {
+        if let Some(branch) = get_excluded_current_branch(&worktree.repo, &options.refs) {
+            eprintln!("The branch `{}', checked out in worktree `{}', is excluded by --refs or \
+                       --exclude-refs; check out another branch there first",
+                      branch,
+                      worktree.name);
+            return E_CURRENT_BRANCH_EXCLUDED;
+        }
+    }
+
+    // Remember where the refs are, so that we don't overwrite anything someone does to them while
+    // we're busy rewriting the history
+    let ref_targets = refs::read_ref_targets(&repo, &options.refs);
+
+    match fetch_submodule_history(&repo, submodule_dir, options.submodule_repo.as_deref()) {
         Ok(_) => {}
         Err(_) => return E_SUBMODULE_FETCH_FAILED,
     }
 
-    if !are_mappings_valid(&repo, &submodule_dir, &mappings, &default_mapping) {
+    if !are_mappings_valid(&repo, submodule_dir, &options.mappings, &options.default_mapping) {
         return E_INVALID_MAPPINGS;
     }
 
+    if !are_renames_valid(&repo, submodule_dir, &options.filter) {
+        return E_INVALID_FILTER;
+    }
+
+    let writer = match build_commit_writer(&repo, &options, false) {
+        Ok(writer) => writer,
+        Err(exit_code) => return exit_code,
+    };
+
     println!("Merging {}...", submodule_dir);
 
     let mut old_id_to_new = HashMap::new();
+    let mut summary = Summary::new();
+    summary.resigned = writer.signer.is_some();
+    let mut commit_map = CommitMap::new(submodule_dir, &options.prefix);
+    if writer.mailmap.is_some() {
+        commit_map.changed.push(String::from("authors"));
+    }
+    if writer.mailmap.is_some() || writer.committer.is_some() {
+        commit_map.changed.push(String::from("committers"));
+    }
+    if options.rewrite_messages {
+        commit_map.changed.push(String::from("messages"));
+    }
+    if options.annotate || options.annotate_updates || options.mode == Mode::Subtree {
+        commit_map.changed.push(String::from("trailers"));
+    }
+    commit_map.record_filter(&options.filter);
+
+    let rewritten = match options.mode {
+        Mode::Merge => {
+            rewrite_submodule_history(&repo,
+                                      &mut old_id_to_new,
+                                      &options,
+                                      &writer,
+                                      &mut summary)
+        }
+        Mode::Flatten | Mode::Subtree => {
+            record_submodule_history(&repo, &mut old_id_to_new, submodule_dir);
+            Ok(())
+        }
+    };
+    if let Err(e) = rewritten {
+        report_signing_failure(&e);
+        return E_SIGNING_NOT_CONFIGURED;
+    }
+    for oid in get_submodule_revwalk(&repo, submodule_dir).filter_map(Result::ok) {
+        commit_map.submodule.push((oid, old_id_to_new[&oid]));
+    }
 
-    rewrite_submodule_history(&repo, &mut old_id_to_new, &submodule_dir);
+    if find_dangling_references_to_submodule(&repo,
+                                             submodule_dir,
+                                             &old_id_to_new,
+                                             &ref_targets,
+                                             &options.mappings,
+                                             &options.default_mapping)
+        .is_some() {
+        return E_FOUND_DANGLING_REFERENCES;
+    }
 
-    match find_dangling_references_to_submodule(&repo,
-                                                &submodule_dir,
-                                                &old_id_to_new,
-                                                &mappings,
-                                                &default_mapping) {
-        Some(_) => return E_FOUND_DANGLING_REFERENCES,
-        None => {}
+    let ref_updates = rewrite_repo_history(&repo,
+                                           &mut old_id_to_new,
+                                           &ref_targets,
+                                           &options,
+                                           &writer,
+                                           &mut summary,
+                                           &mut commit_map);
+    let mut ref_updates = match ref_updates {
+        Ok(ref_updates) => ref_updates,
+        Err(e) => {
+            report_signing_failure(&e);
+            return E_SIGNING_NOT_CONFIGURED;
+        }
+    };
+
+    // Local changes are stashed as late as possible, so that nothing that goes wrong during the
+    // rewrite can leave them in the stash
+    if autostash {
+        let stasher = default_signature(&repo).expect("Couldn't create a signature for the stash");
+        let stash_id = match repo.stash_save(&stasher, AUTOSTASH_MESSAGE, None) {
+            Ok(stash_id) => stash_id,
+            Err(e) => {
+                eprintln!("Couldn't stash local changes: {}", e.message());
+                return E_AUTOSTASH_FAILED;
+            }
+        };
+        println!("Stashed local changes.");
+
+        // The new entry has to be applied to the rewritten HEAD as it is, so it stays on top of
+        // the rewritten stash. If the stash was changed by someone else in the meantime,
+        // `update_refs` notices that and refuses to move anything.
+        let stash_update = ref_updates.iter_mut().find(|update| update.name == refs::STASH_REF);
+        if let Some(update) = stash_update {
+            if refs::stash_entries(&repo).get(1) == Some(&update.old) {
+                update.old = stash_id;
+                update.new = stash_id;
+            }
+        }
     }
 
-    rewrite_repo_history(&repo,
-                         &mut old_id_to_new,
-                         &mappings,
-                         &default_mapping,
-                         &submodule_dir);
+    // Once the refs are moved and submodule's .git is removed, we won't be able to tell which of
+    // the files were changed locally
+    let workdir_state = WorkdirState::read(&repo, submodule_dir);
+    let worktree_states: Vec<WorkdirState> = worktrees.iter()
+        .map(|worktree| WorkdirState::read(&worktree.repo, submodule_dir))
+        .collect();
+
+    // Saved before the refs are moved, so that a map that couldn't be written doesn't leave the
+    // user with a rewrite they can't verify
+    if let Some(ref path) = options.commit_map_file {
+        if let Err(e) = commit_map.write(path) {
+            eprintln!("Couldn't write the commit map to {}: {}", path, e);
+            eprintln!("No refs were changed.");
+            if autostash {
+                apply_autostash(&mut repo);
+            }
+            return E_COMMIT_MAP_FAILED;
+        }
+    }
 
-    // Working directories with and without submodules are pretty much
-    // the same, save for two files:
-    // - submodules have .git in their root directory;
-    // - there's .gitmodules in the root of the repo.
-    remove_dotgit_from_submodule(&submodule_dir);
-    remove_gitmodules();
-    // Git used to think of submodule's directory as a file, because it was
-    // "opaque". We have to update the index in order for Git to realise
-    // that the submodule directory is *just* a directory now.
-    update_index(&repo, &old_id_to_new);
+    if let Err(e) = refs::update_refs(&repo, &ref_updates, &old_id_to_new) {
+        eprintln!("{}", e);
+        eprintln!("No refs were changed; the rewritten history is still in the object database, \
+                   but it isn't referenced by anything.");
+        if autostash {
+            apply_autostash(&mut repo);
+        }
+        return E_REF_UPDATE_FAILED;
+    }
+
+    let mut conflicts = update_workdir(&repo, &workdir_state, &options);
+    for (worktree, state) in worktrees.iter().zip(&worktree_states) {
+        let dir = worktree_dir(&worktree.repo);
+        conflicts.extend(update_workdir(&worktree.repo, state, &options)
+            .into_iter()
+            .map(|path| dir.join(path).to_string_lossy().into_owned()));
+    }
+
+    let autostash_applied = !autostash || apply_autostash(&mut repo);
+
+    summary.print();
+
+    if !conflicts.is_empty() {
+        eprintln!("\nThe history was rewritten, but the following files have local changes that \
+                   would be overwritten or removed by the checkout, so they were left as they \
+                   are:\n");
+        for path in conflicts {
+            eprintln!("{}", path);
+        }
+        eprintln!("\nUse `git diff` to see how they differ from the rewritten HEAD.");
+        return E_CHECKOUT_CONFLICTS;
+    }
+
+    if !autostash_applied {
+        return E_AUTOSTASH_FAILED;
+    }
 
     E_SUCCESS
 }
 
-fn parse_cli_arguments(mappings: &mut HashMap<Oid, Oid>) -> Result<(String, Option<Oid>), i32> {
-    let options = clap::App::new("git-submerge")
-        .version("0.5")
-        .author(crate_authors!())
-        .about("Merge Git submodule into the main repo as if they've never been separate at all")
-        .arg(clap::Arg::with_name("SUBMODULE_DIR")
+// Sets up signing, the mailmap and the committer, as requested by the options. With `check_only`,
+// signing configuration is only validated, and the returned writer doesn't sign anything; setting
+// up the signer for real writes files into the repo and runs the signing program.
+fn build_commit_writer(repo: &Repository,
+                       options: &Options,
+                       check_only: bool)
+                       -> Result<CommitWriter, i32> {
+    let signer = if !options.sign {
+        Ok(None)
+    } else if check_only {
+        Signer::check_config(repo).map(|_| None)
+    } else {
+        Signer::from_config(repo).map(Some)
+    };
+    let signer = match signer {
+        Ok(signer) => signer,
+        Err(e) => {
+            eprintln!("Can't sign commits: {}", e);
+            return Err(E_SIGNING_NOT_CONFIGURED);
+        }
+    };
+
+    let mailmap = if let Some(ref path) = options.mailmap_file {
+        let mailmap = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|buf| {
+                Mailmap::from_buffer(&String::from_utf8_lossy(&buf))
+                    .map_err(|e| String::from(e.message()))
+            });
+        match mailmap {
+            Ok(mailmap) => Some(mailmap),
+            Err(e) => {
+                eprintln!("Couldn't read mailmap from {}: {}", path, e);
+                return Err(E_INVALID_MAILMAP);
+            }
+        }
+    } else if options.use_mailmap {
+        match repo.mailmap() {
+            Ok(mailmap) => Some(mailmap),
+            Err(e) => {
+                eprintln!("Couldn't read the repo's mailmap: {}", e.message());
+                return Err(E_INVALID_MAILMAP);
+            }
+        }
+    } else {
+        None
+    };
+
+    let committer = match options.committer {
+        CommitterPolicy::Keep => Ok(None),
+        CommitterPolicy::Current => repo.signature().map(Some),
+        CommitterPolicy::Explicit { ref name, ref email, time: Some(time) } => {
+            Signature::new(name, email, &time).map(Some)
+        }
+        CommitterPolicy::Explicit { ref name, ref email, time: None } => {
+            Signature::now(name, email).map(Some)
+        }
+    };
+    let committer = match committer {
+        Ok(committer) => committer,
+        Err(e) => {
+            eprintln!("Couldn't figure out the committer: {}", e.message());
+            return Err(E_INVALID_COMMITTER);
+        }
+    };
+
+    // Notes are copied on behalf of the committer, if there's one
+    let notes_signature = match committer {
+        Some(ref committer) => Ok(committer.clone()),
+        None => default_signature(repo),
+    };
+    let notes_signature = match notes_signature {
+        Ok(notes_signature) => notes_signature,
+        Err(e) => {
+            eprintln!("Couldn't figure out who should be recorded as copying notes: {}",
+                      e.message());
+            return Err(E_INVALID_COMMITTER);
+        }
+    };
+
+    Ok(CommitWriter {
+        signer,
+        mailmap,
+        committer,
+        notes_signature,
+    })
+}
+
+// Signing is the only thing that can fail while commits are being written; it's tested before the
+// rewrite starts, but the key might become unavailable midway (say, if gpg-agent times out)
+fn report_signing_failure(e: &str) {
+    eprintln!("Couldn't sign a commit: {}", e);
+    eprintln!("No refs were changed; the commits rewritten so far are still in the object \
+               database, but they aren't referenced by anything.");
+}
+
+// The identity for things we create on user's behalf, like the autostash and copies of notes.
+// libgit2 ignores GIT_COMMITTER_NAME and friends, so on machines without user.name and
+// user.email (CI, for example) we fall back to a made-up one rather than fail.
+fn default_signature(repo: &Repository) -> Result<Signature<'static>, git2::Error> {
+    repo.signature().or_else(|_| Signature::now("git-submerge", "git-submerge@localhost"))
+}
+
+fn open_repo() -> Result<Repository, i32> {
+    Repository::open(".").map_err(|e| {
+        eprintln!("Couldn't find Git repo in the current directory: {}",
+                  e.message());
+        E_NO_GIT_REPO
+    })
+}
+
+fn check_main(options: &Options) -> i32 {
+    let repo = match open_repo() {
+        Ok(repo) => repo,
+        Err(exit_code) => return exit_code,
+    };
+
+    println!("Checking if {} can be merged...\n", options.submodule_dir);
+    check::check(&repo, options)
+}
+
+fn list_main() -> i32 {
+    let repo = match open_repo() {
+        Ok(repo) => repo,
+        Err(exit_code) => return exit_code,
+    };
+
+    // The current branch might not have any commits yet, while other refs do; then none of the
+    // submodules are at HEAD
+    let head_tree = repo.head().and_then(|head| head.peel_to_tree()).ok();
+    let describe = |id: Oid| {
+        let commit = repo.find_commit(id)
+            .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", id));
+        let short_id = commit.as_object()
+            .short_id()
+            .expect("Couldn't abbreviate commit ID");
+        format!("{} {}",
+                String::from_utf8_lossy(&short_id),
+                String::from_utf8_lossy(commit.summary_bytes().unwrap_or(b"")))
+    };
+
+    let histories = submodules::scan_history(&repo);
+    if histories.is_empty() {
+        println!("The history doesn't have any submodules.");
+    }
+    for (path, history) in &histories {
+        let at_head = head_tree.as_ref()
+            .and_then(|tree| gitlink_at(tree, Path::new(path)))
+            .is_some();
+
+        let repos = submodules::open_repositories(&repo, path, history);
+        let available = history.commits
+            .iter()
+            .filter(|id| {
+                repo.find_commit(**id).is_ok() ||
+                repos.iter().any(|r| r.find_commit(**id).is_ok())
+            })
+            .count();
+
+        println!("{}{}", path, if at_head { "" } else { " (not at HEAD)" });
+        for (name, urls) in &history.names {
+            let urls: Vec<&str> = urls.iter().map(|url| url.as_str()).collect();
+            println!("    name: {}, URL: {}",
+                     name,
+                     if urls.is_empty() { String::from("none") } else { urls.join(", ") });
+        }
+        println!("    first commit: {}", describe(history.first_commit));
+        println!("    last commit:  {}", describe(history.last_commit));
+        println!("    submodule commits referenced: {}, {} of them available locally",
+                 history.commits.len(),
+                 available);
+    }
+
+    E_SUCCESS
+}
+
+fn bumps_main(submodule_dir: &str, submodule_repo: Option<&str>, json: bool) -> i32 {
+    let repo = match open_repo() {
+        Ok(repo) => repo,
+        Err(exit_code) => return exit_code,
+    };
+    if !does_submodule_exist(&repo, submodule_dir) {
+        print_submodule_not_found(submodule_dir);
+        return E_SUBMODULE_NOT_FOUND;
+    }
+
+    // Submodule's commits are needed to tell the kinds of updates apart. Without them, we can
+    // still list the updates.
+    if submodules::borrow_objects(&repo, submodule_dir, submodule_repo).is_err() {
+        eprintln!("Couldn't read submodule's history, so the kinds of updates are unknown. Run \
+                   `git submodule update`, or use --submodule-repo.\n");
+    }
+
+    let bumps = bumps::find_bumps(&repo, submodule_dir);
+    if json {
+        bumps::print_json(&bumps);
+    } else {
+        bumps::print_text(&repo, &bumps);
+    }
+
+    E_SUCCESS
+}
+
+fn verify_main(commit_map_file: &str) -> i32 {
+    let repo = match open_repo() {
+        Ok(repo) => repo,
+        Err(exit_code) => return exit_code,
+    };
+
+    let commit_map = match CommitMap::read(commit_map_file) {
+        Ok(commit_map) => commit_map,
+        Err(e) => {
+            eprintln!("Couldn't read the commit map from {}: {}", commit_map_file, e);
+            return E_COMMIT_MAP_FAILED;
+        }
+    };
+
+    for what in &commit_map.changed {
+        match what.as_str() {
+            "trailers" => println!("Ignoring the trailers the rewrite added to commit messages"),
+            "messages" => {
+                println!("Replacing rewritten commit IDs in the original messages before \
+                          comparing them")
+            }
+            _ => println!("Not comparing {}, since the rewrite changed them on purpose", what),
+        }
+    }
+
+    let mismatches = verify::verify(&repo, &commit_map);
+    if mismatches.is_empty() {
+        println!("All {} commits match their rewritten counterparts.",
+                 commit_map.commits.len());
+        E_SUCCESS
+    } else {
+        for mismatch in &mismatches {
+            eprintln!("{}", mismatch);
+        }
+        eprintln!("\nFound {} mismatches in {} commits.",
+                  mismatches.len(),
+                  commit_map.commits.len());
+        E_VERIFICATION_FAILED
+    }
+}
+
+// Arguments of the rewrite itself; `check` accepts them as well, so that it can tell if the
+// rewrite with the same arguments would go through
+fn submerge_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("SUBMODULE_DIR")
             .help("The submodule to merge")
             .required(true)
-            .index(1))
-        .arg(clap::Arg::with_name("mapping")
+            .index(1),
+        clap::Arg::with_name("mapping")
             .value_names(&["commit id 1", "commit id 2"])
             .help("Whenever main repo references submodule's <commit id 1>, the <commit id 2> \
                    will be used instead")
             .short("m")
             .long("mapping")
             .number_of_values(2)
-            .multiple(true))
-        .arg(clap::Arg::with_name("default-mapping")
+            .multiple(true),
+        clap::Arg::with_name("default-mapping")
             .value_name("commit id")
             .help("Whenever main repo references a commit that is neither in submodule's \
                    history nor in mappings (see --mapping), the <commit id> will be used instead")
             .short("d")
             .long("default-mapping")
             .number_of_values(1)
-            .multiple(false))
+            .multiple(false),
+        clap::Arg::with_name("flatten")
+            .help("Replace the submodule with its files in every commit, but don't import \
+                   submodule's history; the result is a linear, self-contained history")
+            .long("flatten"),
+        clap::Arg::with_name("subtree")
+            .help("Keep submodule's commits intact and merge them the way `git merge -s subtree` \
+                   does, recording `git subtree` metadata in the merges")
+            .long("subtree")
+            .conflicts_with("flatten"),
+        clap::Arg::with_name("prefix")
+            .value_name("dir")
+            .help("Put submodule's files into <dir> instead of the submodule's own directory")
+            .long("prefix")
+            .number_of_values(1)
+            .multiple(false),
+        clap::Arg::with_name("include")
+            .value_name("glob")
+            .help("Only import submodule's files that match <glob>")
+            .long("include")
+            .number_of_values(1)
+            .multiple(true)
+            .conflicts_with("subtree"),
+        clap::Arg::with_name("exclude")
+            .value_name("glob")
+            .help("Don't import submodule's files that match <glob>")
+            .long("exclude")
+            .number_of_values(1)
+            .multiple(true)
+            .conflicts_with("subtree"),
+        clap::Arg::with_name("rename")
+            .value_names(&["old dir", "new dir"])
+            .help("Move submodule's files from <old dir> to <new dir>; an empty <new dir> \
+                   means submodule's root")
+            .long("rename")
+            .number_of_values(2)
+            .multiple(true)
+            .conflicts_with("subtree"),
+        clap::Arg::with_name("split-updates")
+            .help("Rewrite commits that updated the submodule and changed other files as two \
+                   commits: one with the changes, and a merge that updates the submodule")
+            .long("split-updates")
+            .conflicts_with("flatten"),
+        clap::Arg::with_name("submodule-parent")
+            .value_name("position")
+            .help("Where to put the submodule commit among the parents of the merges: \
+                   \"last\" keeps `--first-parent` history the same as before, \"first\" makes \
+                   the submodule's history the first-parent one")
+            .long("submodule-parent")
+            .possible_values(&["first", "last"])
+            .default_value("last"),
+        clap::Arg::with_name("annotate")
+            .help("Add \"Submerged-from: <old id>\" trailers to the repo's rewritten commits, and \
+                   \"Submodule-commit: <old id>\" trailers to the submodule's ones")
+            .long("annotate"),
+        clap::Arg::with_name("annotate-updates")
+            .help("Add \"Submodule-update: <old id>..<new id>\" trailers to the merges that \
+                   update the submodule")
+            .long("annotate-updates"),
+        clap::Arg::with_name("rewrite-messages")
+            .help("Replace IDs of rewritten commits mentioned in commit and tag messages with \
+                   their new IDs")
+            .long("rewrite-messages"),
+        clap::Arg::with_name("sign")
+            .help("Sign rewritten commits with the key from user.signingkey, using the program \
+                   chosen by gpg.format, the same way `git commit -S` does")
+            .long("sign"),
+        clap::Arg::with_name("use-mailmap")
+            .help("Fix authors' and committers' names and emails using the repo's mailmap (see \
+                   .mailmap, mailmap.file and mailmap.blob in git-config(1))")
+            .long("use-mailmap"),
+        clap::Arg::with_name("mailmap-file")
+            .help("Fix authors' and committers' names and emails using this mailmap instead of \
+                   the repo's one")
+            .long("mailmap-file")
+            .takes_value(true)
+            .value_name("file"),
+        clap::Arg::with_name("committer")
+            .help("Who should be recorded as the committer of rewritten commits: \"keep\" the \
+                   original committers, the \"current\" user, or \"Name <email>\", optionally \
+                   followed by the time as \"<seconds since epoch> <+hhmm timezone>\"")
+            .long("committer")
+            .takes_value(true)
+            .value_name("policy")
+            .default_value("keep"),
+        clap::Arg::with_name("refs")
+            .help("Only rewrite refs matching this glob (can be repeated). Globs that don't start \
+                   with \"refs/\" are matched against branches, tags and remote-tracking \
+                   branches. By default, all refs are rewritten")
+            .long("refs")
+            .takes_value(true)
+            .value_name("glob")
+            .multiple(true)
+            .number_of_values(1),
+        clap::Arg::with_name("exclude-refs")
+            .help("Leave refs matching this glob untouched (can be repeated)")
+            .long("exclude-refs")
+            .takes_value(true)
+            .value_name("glob")
+            .multiple(true)
+            .number_of_values(1),
+        clap::Arg::with_name("branches-and-tags-only")
+            .help("Only rewrite local branches and tags, leaving remote-tracking branches, notes, \
+                   stash and other refs untouched")
+            .long("branches-and-tags-only")
+            .conflicts_with("refs"),
+        clap::Arg::with_name("autostash")
+            .help("Stash local changes to the main repo before the rewrite, and re-apply them \
+                   on top of the rewritten HEAD afterwards")
+            .long("autostash"),
+        clap::Arg::with_name("submodule-repo")
+            .help("Fetch submodule's history from <repo> instead of submodule's working copy. \
+                   Needed if the submodule doesn't exist at HEAD, and Git doesn't have its \
+                   repository in .git/modules either")
+            .long("submodule-repo")
+            .takes_value(true)
+            .value_name("repo"),
+    ]
+}
+
+fn parse_cli_arguments() -> Result<Command, i32> {
+    let options = clap::App::new("git-submerge")
+        .version("0.5")
+        .author(crate_authors!())
+        .about("Merge Git submodule into the main repo as if they've never been separate at all")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
+        .setting(clap::AppSettings::ArgsNegateSubcommands)
+        .args(&submerge_args())
+        .arg(clap::Arg::with_name("write-commit-map")
+            .help("Save the correspondence between original and rewritten commits to <file>, \
+                   for use with `verify`")
+            .long("write-commit-map")
+            .takes_value(true)
+            .value_name("file"))
+        .subcommand(clap::SubCommand::with_name("check")
+            .about("Report what the rewrite would do, and what would stop it, without changing \
+                    anything")
+            .args(&submerge_args()))
+        .subcommand(clap::SubCommand::with_name("list")
+            .about("List all the submodules that ever existed in the repo's history"))
+        .subcommand(clap::SubCommand::with_name("bumps")
+            .about("List the commits that updated the submodule, and how")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule to report on")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("json")
+                .help("Print the report as JSON")
+                .long("json"))
+            .arg(clap::Arg::with_name("submodule-repo")
+                .help("Read submodule's commits from <repo> instead of submodule's working copy")
+                .long("submodule-repo")
+                .takes_value(true)
+                .value_name("repo")))
+        .subcommand(clap::SubCommand::with_name("verify")
+            .about("Check that a rewrite preserved all the content, authorship and ancestry of \
+                    the original commits")
+            .arg(clap::Arg::with_name("COMMIT_MAP")
+                .help("The file saved by --write-commit-map")
+                .required(true)
+                .index(1)))
         .get_matches();
 
+    if let Some(verify) = options.subcommand_matches("verify") {
+        return Ok(Command::Verify {
+            commit_map_file: String::from(verify.value_of("COMMIT_MAP")
+                .expect("clap should have made COMMIT_MAP required")),
+        });
+    }
+
+    if let Some(bumps) = options.subcommand_matches("bumps") {
+        return Ok(Command::Bumps {
+            submodule_dir: String::from(bumps.value_of("SUBMODULE_DIR")
+                .expect("clap should have made SUBMODULE_DIR required")),
+            submodule_repo: bumps.value_of("submodule-repo").map(String::from),
+            json: bumps.is_present("json"),
+        });
+    }
+
+    if options.subcommand_matches("list").is_some() {
+        return Ok(Command::List);
+    }
+
+    if let Some(check) = options.subcommand_matches("check") {
+        return parse_options(check).map(|options| Command::Check(Box::new(options)));
+    }
+
+    parse_options(&options).map(|options| Command::Submerge(Box::new(options)))
+}
+
+fn parse_options(options: &clap::ArgMatches) -> Result<Options, i32> {
+    let mut mappings = HashMap::new();
     match options.values_of("mapping") {
         None => {}
         Some(values) => {
@@ -164,26 +891,186 @@ fn parse_cli_arguments(mappings: &mut HashMap<Oid, Oid>) -> Result<(String, Opti
         None
     };
 
+    let mode = if options.is_present("flatten") {
+        Mode::Flatten
+    } else if options.is_present("subtree") {
+        Mode::Subtree
+    } else {
+        Mode::Merge
+    };
+
     // We can safely use unwrap() here because the argument is marked as "required" and Clap checks
     // its presence for us.
-    Ok((String::from(options.value_of("SUBMODULE_DIR").unwrap()), default_mapping))
+    let submodule_dir = String::from(options.value_of("SUBMODULE_DIR").unwrap()
+        .trim_matches('/'));
+    let prefix = match options.value_of("prefix") {
+        Some(prefix) => String::from(prefix.trim_matches('/')),
+        None => submodule_dir.clone(),
+    };
+    if prefix.is_empty() {
+        eprintln!("--prefix can't point at the root of the repository");
+        return Err(E_INVALID_PREFIX);
+    }
+
+    let values_of = |name| -> Vec<&str> {
+        options.values_of(name).map(|v| v.collect()).unwrap_or_default()
+    };
+
+    let include = values_of("include");
+    let exclude = values_of("exclude");
+    let mut renames = Vec::new();
+    if let Some(values) = options.values_of("rename") {
+        let values: Vec<&str> = values.collect();
+        for pair in values.chunks(2) {
+            renames.push((String::from(pair[0].trim_matches('/')),
+                          String::from(pair[1].trim_matches('/'))));
+        }
+    }
+    let filter = match PathFilter::new(&include, &exclude, renames) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Couldn't parse --include or --exclude globs: {}", e.message());
+            return Err(E_INVALID_FILTER);
+        }
+    };
+
+    let mut refs = values_of("refs");
+    if refs.is_empty() && options.is_present("branches-and-tags-only") {
+        refs = vec!["refs/heads/*", "refs/tags/*"];
+    }
+    let refs = RefFilter::new(&refs, &values_of("exclude-refs"));
+
+    let committer = match options.value_of("committer") {
+        Some("current") => CommitterPolicy::Current,
+        Some("keep") | None => CommitterPolicy::Keep,
+        Some(identity) => {
+            match parse_identity(identity) {
+                Some((name, email, time)) => CommitterPolicy::Explicit { name, email, time },
+                None => {
+                    eprintln!("--committer should be \"keep\", \"current\", or \"Name <email>\" \
+                               optionally followed by \"<seconds> <+hhmm>\"; got `{}'",
+                              identity);
+                    return Err(E_INVALID_COMMITTER);
+                }
+            }
+        }
+    };
+
+    Ok(Options {
+        submodule_dir,
+        prefix,
+        filter,
+        mappings,
+        default_mapping,
+        mode,
+        split_updates: options.is_present("split-updates"),
+        parent_order: match options.value_of("submodule-parent") {
+            Some("first") => ParentOrder::First,
+            _ => ParentOrder::Last,
+        },
+        annotate: options.is_present("annotate"),
+        annotate_updates: options.is_present("annotate-updates"),
+        rewrite_messages: options.is_present("rewrite-messages"),
+        sign: options.is_present("sign"),
+        use_mailmap: options.is_present("use-mailmap"),
+        mailmap_file: options.value_of("mailmap-file").map(String::from),
+        committer,
+        refs,
+        autostash: options.is_present("autostash"),
+        commit_map_file: options.value_of("write-commit-map").map(String::from),
+        submodule_repo: options.value_of("submodule-repo").map(String::from),
+    })
 }
 
-fn is_workdir_clean(repo: &Repository) -> bool {
-    let mut statusopts = git2::StatusOptions::new();
-    statusopts.include_untracked(false);
-    statusopts.include_ignored(false);
-    statusopts.include_unmodified(false);
-    statusopts.exclude_submodules(false);
-    statusopts.recurse_untracked_dirs(false);
-    statusopts.recurse_ignored_dirs(false);
-    let statuses = repo.statuses(Some(&mut statusopts))
-        .expect("Couldn't get statuses from the repo");
-    statuses.iter().count() == 0
+// Parses "Name <email>", optionally followed by Git's internal time format: "1234567890 +0300"
+fn parse_identity(identity: &str) -> Option<(String, String, Option<Time>)> {
+    let email_start = identity.find('<')?;
+    let email_end = identity.rfind('>')?;
+    if email_end < email_start {
+        return None;
+    }
+
+    let name = identity[..email_start].trim();
+    let email = identity[email_start + 1..email_end].trim();
+    if name.is_empty() || email.is_empty() {
+        return None;
+    }
+
+    let rest: Vec<&str> = identity[email_end + 1..].split_whitespace().collect();
+    let time = match rest.len() {
+        0 => None,
+        2 => {
+            let seconds = rest[0].trim_start_matches('@').parse::<i64>().ok()?;
+            let timezone = rest[1];
+            // Checking for ASCII first makes the byte slicing below safe
+            if !timezone.is_ascii() || timezone.len() != 5 ||
+               !timezone[1..].chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let sign = match &timezone[..1] {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours = timezone[1..3].parse::<i32>().ok()?;
+            let minutes = timezone[3..].parse::<i32>().ok()?;
+            Some(Time::new(seconds, sign * (hours * 60 + minutes)))
+        }
+        _ => return None,
+    };
+
+    Some((String::from(name), String::from(email), time))
+}
+
+fn print_dirty_paths(dirty_paths: &[DirtyPath], autostash: bool) {
+    for dirty in dirty_paths {
+        let note = if autostash && !dirty.stashable {
+            " (can't be stashed)"
+        } else {
+            ""
+        };
+        eprintln!("    {}: {}{}", dirty.path, dirty.reason, note);
+    }
+}
+
+// Applies the stash entry created by --autostash, and drops it if the changes applied cleanly.
+// Otherwise, the entry is kept, so nothing gets lost.
+fn apply_autostash(repo: &mut Repository) -> bool {
+    let mut options = git2::StashApplyOptions::new();
+    options.reinstantiate_index();
+    // libgit2 considers the stash applied even if there were conflicts, which it writes to the
+    // index, so we have to check for them ourselves
+    let result = repo.stash_apply(0, Some(&mut options))
+        .map_err(|e| String::from(e.message()))
+        .and_then(|_| {
+            let index = repo.index().expect("Couldn't obtain repo's index");
+            if index.has_conflicts() {
+                Err(String::from("some of the changes conflict with the rewritten HEAD"))
+            } else {
+                Ok(())
+            }
+        });
+
+    match result {
+        Ok(()) => {
+            repo.stash_drop(0).expect("Couldn't drop the stash entry created by --autostash");
+            println!("Re-applied stashed changes.");
+            true
+        }
+        Err(e) => {
+            eprintln!("Couldn't re-apply stashed changes: {}", e);
+            eprintln!("They're saved in stash@{{0}}; once the conflicts are resolved, drop it with \
+                       `git stash drop`.");
+            false
+        }
+    }
 }
 
+// The submodule doesn't have to exist at HEAD: it might have been removed at some point, and we can
+// still merge its history into the commits that had it
 fn does_submodule_exist(repo: &Repository, submodule_dir: &str) -> bool {
-    repo.find_submodule(submodule_dir).is_ok()
+    repo.find_submodule(submodule_dir).is_ok() ||
+    !submodules::gitlinks_at(repo, submodule_dir).is_empty()
 }
 
 // Checks if all the values in the `mappings` exist in submodule's history
@@ -197,7 +1084,7 @@ fn are_mappings_valid(repo: &Repository,
         commits.insert(oid);
     };
 
-    let revwalk = get_submodule_revwalk(&repo, &submodule_dir);
+    let revwalk = get_submodule_revwalk(repo, submodule_dir);
     for maybe_oid in revwalk {
         match maybe_oid {
             Ok(oid) => {
@@ -211,31 +1098,88 @@ fn are_mappings_valid(repo: &Repository,
         eprintln!("Commit {} not found in submodule's history.", commit);
     }
 
-    commits.len() == 0
+    commits.is_empty()
+}
+
+// Checks that renames don't put two of the submodule's files at the same path in any of its commits
+fn are_renames_valid(repo: &Repository, submodule_dir: &str, filter: &PathFilter) -> bool {
+    if !filter.has_renames() {
+        return true;
+    }
+
+    let mut checked_trees = HashSet::new();
+    for oid in get_submodule_revwalk(repo, submodule_dir).filter_map(Result::ok) {
+        let tree = repo.find_commit(oid)
+            .and_then(|commit| commit.tree())
+            .unwrap_or_else(|_| panic!("Couldn't obtain the tree of submodule commit {}", oid));
+        if !checked_trees.insert(tree.id()) {
+            continue;
+        }
+
+        let mut index = Index::new().expect("Couldn't create an in-memory index");
+        index.read_tree(&tree)
+            .unwrap_or_else(|_| panic!("Couldn't read the tree {} into index", tree.id()));
+        let paths: Vec<Vec<u8>> = index.iter().map(|entry| entry.path).collect();
+        if let Some((first, second, path)) =
+            filter.find_collision(paths.iter().map(|path| path.as_slice())) {
+            eprintln!("Renames put both `{}' and `{}' from submodule commit {} at `{}'; change \
+                       --rename or --exclude one of them.",
+                      String::from_utf8_lossy(&first),
+                      String::from_utf8_lossy(&second),
+                      oid,
+                      String::from_utf8_lossy(&path));
+            return false;
+        }
+    }
+
+    true
 }
 
 fn get_submodule_revwalk<'repo>(repo: &'repo Repository, submodule_dir: &str) -> Revwalk<'repo> {
-    let submodule = repo.find_submodule(submodule_dir)
-        .expect("Couldn't find the submodule with expected path");
-    let submodule_head = submodule.head_id()
-        .expect("Couldn't obtain submodule's HEAD");
+    // If the submodule was removed, there's no HEAD to start from, so we take all the commits the
+    // main repo referenced, as long as we have them
+    let submodule_heads = match repo.find_submodule(submodule_dir).ok().and_then(|s| s.head_id()) {
+        Some(submodule_head) => vec![submodule_head],
+        None => {
+            submodules::gitlinks_at(repo, submodule_dir)
+                .into_iter()
+                .filter(|id| repo.find_commit(*id).is_ok())
+                .collect()
+        }
+    };
 
     let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
     // "Topological" and reverse means "parents are always visited before their children".
     // We need that in order to be sure that our old-to-new-ids map always contains everything we
     // need it to contain.
-    revwalk.set_sorting(git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL);
+    revwalk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL)
+        .expect("Couldn't set sorting on RevWalk");
     // TODO (#6): push all branches and tags, not just HEAD
-    revwalk.push(submodule_head).expect("Couldn't add submodule's HEAD to RevWalk");
+    for submodule_head in submodule_heads {
+        revwalk.push(submodule_head).expect("Couldn't add submodule's HEAD to RevWalk");
+    }
 
     revwalk
 }
 
-fn fetch_submodule_history(repo: &Repository, submodule_dir: &str) -> Result<(), ()> {
-    let submodule_url = String::from("./") + submodule_dir;
+fn fetch_submodule_history(repo: &Repository,
+                           submodule_dir: &str,
+                           submodule_repo: Option<&str>)
+                           -> Result<(), ()> {
+    let submodule_url = match submodules::repository_location(repo, submodule_dir, submodule_repo) {
+        Some(url) => url,
+        None => {
+            eprintln!("The submodule doesn't exist at HEAD, and Git doesn't have its repository \
+                       in .git/modules. Use --submodule-repo to tell where to fetch its history \
+                       from.");
+            return Err(());
+        }
+    };
     let mut remote = repo.remote_anonymous(&submodule_url)
         .expect("Couldn't create an anonymous remote");
-    match remote.fetch(&[], None, None) {
+    let fetched = remote.fetch(&[] as &[&str], None, None)
+        .or_else(|_| submodules::copy_objects(repo, &submodule_url));
+    match fetched {
         Ok(_) => Ok(()),
         Err(_) => {
             eprintln!("Couldn't fetch submodule's history!  Have you forgot to run \
@@ -247,117 +1191,151 @@ fn fetch_submodule_history(repo: &Repository, submodule_dir: &str) -> Result<(),
 
 fn rewrite_submodule_history(repo: &Repository,
                              old_id_to_new: &mut HashMap<Oid, Oid>,
-                             submodule_dir: &str) {
-    let revwalk = get_submodule_revwalk(&repo, &submodule_dir);
+                             options: &Options,
+                             writer: &CommitWriter,
+                             summary: &mut Summary)
+                             -> Result<(), String> {
+    let revwalk = get_submodule_revwalk(repo, &options.submodule_dir);
     for maybe_oid in revwalk {
         match maybe_oid {
             Ok(oid) => {
                 let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+                    .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
                 let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
-                let mut old_index = Index::new()
-                    .expect("Couldn't create an in-memory index for commit");
-                let mut new_index = Index::new().expect("Couldn't create an in-memory index");
-                old_index.read_tree(&tree)
-                    .expect(&format!("Couldn't read the commit {} into index", oid));
-
-                // Obtain the new tree, where everything from the old one is moved under
-                // a directory named after the submodule
-                for entry in old_index.iter() {
-                    let mut new_entry = entry;
-
-                    let mut new_path = String::from(submodule_dir);
-                    new_path += "/";
-                    new_path += &String::from_utf8(new_entry.path)
-                        .expect("Failed to convert a path to str");
-
-                    new_entry.path = new_path.into_bytes();
-                    new_index.add(&new_entry).expect("Couldn't add an entry to the index");
-                }
-                let tree_id = new_index.write_tree_to(&repo)
-                    .expect("Couldn't write the index into a tree");
+                    .unwrap_or_else(|_| {
+                        panic!("Couldn't obtain the tree of a commit with ID {}", oid)
+                    });
+                let tree_id = build_submodule_tree(repo, &tree, options);
                 old_id_to_new.insert(tree.id(), tree_id);
-                let tree = repo.find_tree(tree_id)
-                    .expect("Couldn't retrieve the tree we just created");
 
-                let parents = {
-                    let mut p: Vec<Commit> = Vec::new();
-                    for parent_id in commit.parent_ids() {
-                        let new_parent_id = old_id_to_new[&parent_id];
-                        let parent = repo.find_commit(new_parent_id)
-                            .expect("Couldn't find parent commit by its id");
-                        p.push(parent);
-                    }
-                    p
-                };
+                let parents = commit.parent_ids()
+                    .map(|parent_id| old_id_to_new[&parent_id])
+                    .collect();
 
-                let mut parents_refs: Vec<&Commit> = Vec::new();
-                for i in 0..parents.len() {
-                    parents_refs.push(&parents[i]);
+                let (mut new_commit, dropped_headers) =
+                    NewCommit::based_on(&commit, tree_id, parents);
+                summary.record_dropped_headers(oid, dropped_headers);
+                if options.rewrite_messages {
+                    new_commit.message =
+                        message::rewrite_commit_ids(repo, &new_commit.message, old_id_to_new);
+                }
+                if options.annotate {
+                    new_commit.message =
+                        message::append_trailers(&new_commit.message,
+                                                 &[("Submodule-commit", oid.to_string())]);
                 }
-                let new_commit_id = repo.commit(None,
-                            &commit.author(),
-                            &commit.committer(),
-                            &commit.message().expect("Couldn't retrieve commit's message"),
-                            &tree,
-                            &parents_refs[..])
-                    .expect("Failed to commit");
+
+                let new_commit_id = writer.write(repo, new_commit)?;
 
                 old_id_to_new.insert(oid, new_commit_id);
             }
             Err(e) => eprintln!("Error walking the submodule's history: {:?}", e),
         }
     }
+
+    Ok(())
+}
+
+// Obtains the new tree, where everything from the submodule's tree is filtered, renamed, and moved
+// under the prefix directory
+fn build_submodule_tree(repo: &Repository, tree: &Tree, options: &Options) -> Oid {
+    let mut old_index = Index::new()
+        .expect("Couldn't create an in-memory index for commit");
+    let mut new_index = Index::new().expect("Couldn't create an in-memory index");
+    old_index.read_tree(tree)
+        .unwrap_or_else(|_| panic!("Couldn't read the tree {} into index", tree.id()));
+
+    for entry in old_index.iter() {
+        let mut new_entry = entry;
+
+        // Paths are kept as bytes all the way through, since Git doesn't require them to be valid
+        // UTF-8
+        let mut new_path = options.prefix.clone().into_bytes();
+        new_path.push(b'/');
+        match options.filter.apply(&new_entry.path) {
+            Some(filtered_path) => new_path.extend_from_slice(&filtered_path),
+            None => continue,
+        }
+
+        new_entry.path = new_path;
+        new_index.add(&new_entry).expect("Couldn't add an entry to the index");
+    }
+
+    new_index.write_tree_to(repo)
+        .expect("Couldn't write the index into a tree")
 }
 
-fn find_dangling_references_to_submodule<'repo>(repo: &'repo Repository,
-                                                submodule_dir: &str,
-                                                old_id_to_new: &HashMap<Oid, Oid>,
-                                                mappings: &HashMap<Oid, Oid>,
-                                                default_mapping: &Option<Oid>)
-                                                -> Option<bool> {
+// Returns the ID of the submodule's tree as it should appear under the prefix directory, or None
+// if the filters left nothing of it. Trees we've already seen are looked up in `cache`.
+fn filtered_subtree_id(repo: &Repository,
+                       tree: &Tree,
+                       options: &Options,
+                       cache: &mut HashMap<Oid, Option<Oid>>)
+                       -> Option<Oid> {
+    if options.filter.is_empty() {
+        return Some(tree.id());
+    }
+
+    if let Some(id) = cache.get(&tree.id()) {
+        return *id;
+    }
+
+    let new_tree_id = build_submodule_tree(repo, tree, options);
+    let subtree_id = repo.find_tree(new_tree_id)
+        .expect("Couldn't retrieve the tree we just created")
+        .get_path(Path::new(&options.prefix))
+        .ok()
+        .map(|te| te.id());
+    cache.insert(tree.id(), subtree_id);
+    subtree_id
+}
+
+// In flatten and subtree modes, submodule's commits are kept as they are, so we map each of them
+// into itself.
+// That way, the rest of the program can still tell which commits are in the submodule's history.
+fn record_submodule_history(repo: &Repository,
+                            old_id_to_new: &mut HashMap<Oid, Oid>,
+                            submodule_dir: &str) {
+    let revwalk = get_submodule_revwalk(repo, submodule_dir);
+    for maybe_oid in revwalk {
+        match maybe_oid {
+            Ok(oid) => {
+                old_id_to_new.insert(oid, oid);
+            }
+            Err(e) => eprintln!("Error walking the submodule's history: {:?}", e),
+        }
+    }
+}
+
+fn find_dangling_references_to_submodule(repo: &Repository,
+                                         submodule_dir: &str,
+                                         old_id_to_new: &HashMap<Oid, Oid>,
+                                         ref_targets: &HashMap<String, Oid>,
+                                         mappings: &HashMap<Oid, Oid>,
+                                         default_mapping: &Option<Oid>)
+                                         -> Option<bool> {
     let submodule_path = Path::new(submodule_dir);
 
     let known_submodule_commits: HashSet<&Oid> = old_id_to_new.keys().collect();
     let mut dangling_references = HashSet::new();
 
-    let revwalk = get_repo_revwalk(&repo);
+    let revwalk = get_repo_revwalk(repo, ref_targets);
 
     for maybe_oid in revwalk {
         match maybe_oid {
             Ok(oid) => {
                 let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+                    .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
                 let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
-
-                let submodule_subdir = match tree.get_path(submodule_path) {
-                    Ok(tree) => {
-                        // We're only interested in gitlinks
-                        if tree.filemode() != 0o160000 {
-                            continue;
-                        }
-                        tree
-                    },
-                    Err(e) => {
-                        if e.code() == git2::ErrorCode::NotFound &&
-                           e.class() == git2::ErrorClass::Tree {
-                            // It's okay. The tree lacks the subtree corresponding to the
-                            // submodule. In other words, the commit doesn't include the submodule.
-                            // That's totally fine. Let's  move on.
-                            continue;
-                        } else {
-                            // Unexpected error; let's report it and abort the program
-                            panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                        };
-                    }
+                    .unwrap_or_else(|_| {
+                        panic!("Couldn't obtain the tree of a commit with ID {}", oid)
+                    });
+
+                // We're only interested in commits that have the submodule
+                let submodule_commit_id = match gitlink_at(&tree, submodule_path) {
+                    Some(submodule_commit_id) => submodule_commit_id,
+                    None => continue,
                 };
-
-                // **INVARIANT**: if we got this far, current commit contains a submodule and
-                // should be rewritten
-
-                let submodule_commit_id = submodule_subdir.id();
                 if !known_submodule_commits.contains(&submodule_commit_id) &&
                    !mappings.contains_key(&submodule_commit_id) &&
                    default_mapping.is_none() {
@@ -384,136 +1362,224 @@ fn find_dangling_references_to_submodule<'repo>(repo: &'repo Repository,
     }
 }
 
-fn get_repo_revwalk<'repo>(repo: &'repo Repository) -> Revwalk<'repo> {
-    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
-    revwalk.set_sorting(git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL);
-    let head = repo.head().expect("Couldn't obtain repo's HEAD");
-    let head_id = head.target().expect("Couldn't resolve repo's HEAD to a commit ID");
-    revwalk.push(head_id).expect("Couldn't add repo's HEAD to RevWalk");
+// Returns the submodule commit that should be used in place of `id`: either the commit itself, or
+// its replacement from --mapping or --default-mapping. The result is an ID from the submodule's
+// original history.
+fn resolve_submodule_commit(id: &Oid,
+                            old_id_to_new: &HashMap<Oid, Oid>,
+                            options: &Options)
+                            -> Oid {
+    let mapped = match options.mappings.get(id) {
+        Some(mapped) => *mapped,
+        None => *id,
+    };
 
-    for (name, id) in get_branch_to_id_map(&repo) {
-        revwalk.push(id)
-            .expect(&format!("Couldn't push branch `{}' to RevWalk", name));
+    if old_id_to_new.contains_key(&mapped) {
+        mapped
+    } else {
+        options.default_mapping
+            .unwrap_or_else(|| {
+                panic!("Found a commit that isn't in mappings, and default-mapping is empty: {}",
+                       mapped)
+            })
     }
+}
 
-    revwalk
+fn classify_update(repo: &Repository, old: Option<Oid>, new: Oid) -> UpdateKind {
+    let old = match old {
+        Some(id) => id,
+        None => return UpdateKind::Added,
+    };
+
+    let is_descendant = |commit, ancestor| {
+        repo.graph_descendant_of(commit, ancestor)
+            .expect("Couldn't check if one commit descends from another")
+    };
+    if new == old || is_descendant(new, old) {
+        UpdateKind::FastForward
+    } else if is_descendant(old, new) {
+        UpdateKind::Rollback
+    } else {
+        UpdateKind::NonFastForward
+    }
+}
+
+// Returns the submodule commits recorded in the commit's parents, along with the one recorded in
+// its first parent. Parents that don't have the submodule are skipped.
+fn parent_submodule_states(commit: &Commit, submodule_path: &Path) -> (HashSet<Oid>, Option<Oid>) {
+    let mut parent_subtree_ids = HashSet::new();
+    let mut first_parent_subtree_id = None;
+    for (i, parent) in commit.parents().enumerate() {
+        let parent_tree = parent.tree().expect("Couldn't obtain parent's tree");
+        let parent_subdir_tree_id = parent_tree.get_path(submodule_path)
+            .map(|x| x.id());
+
+        match parent_subdir_tree_id {
+            Ok(id) => {
+                parent_subtree_ids.insert(id);
+                if i == 0 {
+                    first_parent_subtree_id = Some(id);
+                }
+            }
+            Err(e) => {
+                if e.code() == git2::ErrorCode::NotFound &&
+                   e.class() == git2::ErrorClass::Tree {
+                    continue;
+                } else {
+                    panic!("Error getting submodule's subdir from the tree: {:?}", e);
+                };
+            }
+        }
+    }
+    (parent_subtree_ids, first_parent_subtree_id)
 }
 
-fn get_branch_to_id_map(repo: &Repository) -> HashMap<String, Oid> {
-    let mut result = HashMap::new();
-
-    let branches = repo.branches(Some(git2::BranchType::Local))
-        .expect("Couldn't obtain an iterator over local branches");
-    for maybe_branch in branches {
-        match maybe_branch {
-            Ok((branch, _)) => {
-                let name = branch.name()
-                    .expect("Couldn't get branch' name")
-                    .expect("Branch name is not valid UTF-8");
-                let id = branch.get()
-                    .peel(git2::ObjectType::Commit)
-                    .expect("Couldn't convert branch into a Commit")
-                    .id();
-                result.insert(String::from(name), id);
+// Returns the submodule commit recorded at `path`, if the tree has a gitlink there
+fn gitlink_at(tree: &Tree, path: &Path) -> Option<Oid> {
+    match tree.get_path(path) {
+        Ok(ref entry) if entry.filemode() == 0o160000 => Some(entry.id()),
+        Ok(_) => None,
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound &&
+                      e.class() == git2::ErrorClass::Tree => None,
+        Err(e) => panic!("Error getting submodule's subdir from the tree: {:?}", e),
+    }
+}
+
+fn print_submodule_not_found(submodule_dir: &str) {
+    eprintln!("Couldn't find a submodule at `{}', neither at HEAD nor anywhere in the history",
+              submodule_dir);
+}
+
+fn get_repo_revwalk<'repo>(repo: &'repo Repository,
+                           ref_targets: &HashMap<String, Oid>)
+                           -> Revwalk<'repo> {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL)
+        .expect("Couldn't set sorting on RevWalk");
+    // HEAD is unborn if the current branch doesn't have any commits yet; other refs might still
+    // have some history
+    if let Some(head_id) = repo.head().ok().and_then(|head| head.target()) {
+        revwalk.push(head_id).expect("Couldn't add repo's HEAD to RevWalk");
+    }
+
+    // Older stash entries are only referenced by the stash's reflog, but they're rewritten along
+    // with the latest one
+    if ref_targets.contains_key(refs::STASH_REF) {
+        for id in refs::stash_entries(repo) {
+            if repo.find_commit(id).is_ok() {
+                revwalk.push(id).unwrap_or_else(|_| panic!("Couldn't push stash entry {}", id));
             }
-            Err(e) => eprintln!("Error walking the branches: {:?}", e),
         }
     }
 
-    result
+    for (name, id) in ref_targets {
+        // Notes aren't part of the history; they're carried over by `copy_notes`
+        if name.starts_with("refs/notes/") {
+            continue;
+        }
+        // Annotated tags are peeled to the commits they point at. Tags can point at trees and
+        // blobs, too; we have nothing to do with those.
+        let commit = repo.find_object(*id, None)
+            .and_then(|object| object.peel(git2::ObjectType::Commit));
+        if let Ok(commit) = commit {
+            revwalk.push(commit.id())
+                .unwrap_or_else(|_| panic!("Couldn't push ref `{}' to RevWalk", name));
+        }
+    }
+
+    revwalk
+}
+
+// Returns the name of the current branch if it's not among the selected refs
+fn get_excluded_current_branch(repo: &Repository, refs: &RefFilter) -> Option<String> {
+    let head = repo.head().ok()?;
+    if !head.is_branch() {
+        return None;
+    }
+
+    let name = String::from_utf8_lossy(head.name_bytes()).into_owned();
+    let selected = refs.select(repo).expect("Couldn't obtain the list of refs");
+    if selected.contains(&name) {
+        None
+    } else {
+        Some(String::from_utf8_lossy(head.shorthand_bytes()).into_owned())
+    }
 }
 
+// Rewrites the history and returns the list of refs that should be moved to the new one
 fn rewrite_repo_history(repo: &Repository,
                         old_id_to_new: &mut HashMap<Oid, Oid>,
-                        mappings: &HashMap<Oid, Oid>,
-                        default_mapping: &Option<Oid>,
-                        submodule_dir: &str) {
-    let revwalk = get_repo_revwalk(&repo);
-    let submodule_path = Path::new(submodule_dir);
+                        ref_targets: &HashMap<String, Oid>,
+                        options: &Options,
+                        writer: &CommitWriter,
+                        summary: &mut Summary,
+                        commit_map: &mut CommitMap)
+                        -> Result<Vec<RefUpdate>, String> {
+    let revwalk = get_repo_revwalk(repo, ref_targets);
+    let submodule_path = Path::new(&options.submodule_dir);
+    let prefix_path = Path::new(&options.prefix);
+    let mode = options.mode;
+    let mut subtree_cache = HashMap::new();
 
     for maybe_oid in revwalk {
         match maybe_oid {
             Ok(oid) => {
                 let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+                    .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
                 let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
-
-                let submodule_subdir = match tree.get_path(submodule_path) {
-                    Ok(tree) => {
-                        // We're only interested in gitlinks
-                        if tree.filemode() != 0o160000 {
-                            continue;
-                        };
-                        tree
-                    },
-                    Err(e) => {
-                        if e.code() == git2::ErrorCode::NotFound &&
-                           e.class() == git2::ErrorClass::Tree {
-                            // It's okay. The tree lacks the subtree corresponding to the
-                            // submodule. In other words, the commit doesn't include the submodule.
-                            // That's totally fine. Let's map it into itself and move on.
-                            old_id_to_new.insert(oid, oid);
-                            continue;
-                        } else {
-                            // Unexpected error; let's report it and abort the program
-                            panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                        };
+                    .unwrap_or_else(|_| {
+                        panic!("Couldn't obtain the tree of a commit with ID {}", oid)
+                    });
+
+                let submodule_commit_id = match gitlink_at(&tree, submodule_path) {
+                    Some(submodule_commit_id) => submodule_commit_id,
+                    None => {
+                        // The commit doesn't include the submodule (yet, or anymore), or
+                        // something else took submodule's place, which is not ours to touch.
+                        // Either way, its tree stays the same.
+                        let new_commit_id =
+                            carry_over_commit(repo, &commit, old_id_to_new, options, writer,
+                                              summary)?;
+                        old_id_to_new.insert(oid, new_commit_id);
+                        commit_map.commits.push((oid, new_commit_id));
+                        continue;
                     }
                 };
 
                 // **INVARIANT**: if we got this far, current commit contains a submodule and
                 // should be rewritten
 
-                let submodule_commit_id = submodule_subdir.id();
-                let mut new_submodule_commit_id = match mappings.get(&submodule_commit_id) {
-                    Some(id) => *id,
-                    None => submodule_commit_id,
-                };
-                new_submodule_commit_id = match old_id_to_new.get(&new_submodule_commit_id) {
-                    Some(id) => *id,
-                    None => {
-                        let mapped =
-                            default_mapping
-                            .expect(&format!("Found a commit that isn't in mappings, \
-                                              and default-mapping is empty: {}",
-                                              new_submodule_commit_id));
-                        old_id_to_new[&mapped]
+                let resolved_submodule_commit_id =
+                    resolve_submodule_commit(&submodule_commit_id, old_id_to_new, options);
+                let new_submodule_commit_id = old_id_to_new[&resolved_submodule_commit_id];
+                let submodule_commit = repo.find_commit(new_submodule_commit_id)
+                    .unwrap_or_else(|_| {
+                        panic!("Couldn't obtain submodule's commit with ID {}",
+                               new_submodule_commit_id)
+                    });
+                let submodule_tree = submodule_commit.tree()
+                    .expect("Couldn't obtain submodule's tree");
+                let subtree_id = match mode {
+                    // Rewritten submodule commits already have everything filtered and moved
+                    // under the prefix directory
+                    Mode::Merge => submodule_tree.get_path(prefix_path).ok().map(|te| te.id()),
+                    Mode::Flatten | Mode::Subtree => {
+                        filtered_subtree_id(repo, &submodule_tree, options, &mut subtree_cache)
                     }
                 };
-                let submodule_commit = repo.find_commit(new_submodule_commit_id)
-                    .expect(&format!("Couldn't obtain submodule's commit with ID {}",
-                                     new_submodule_commit_id));
-                let subtree_id = submodule_commit.tree()
-                    .and_then(|t| t.get_path(submodule_path))
-                    .and_then(|te| Ok(te.id()))
-                    .expect("Couldn't obtain submodule's subtree ID");
+                commit_map.gitlinks.insert(submodule_commit_id, resolved_submodule_commit_id);
+                commit_map.subtrees.insert(resolved_submodule_commit_id, subtree_id);
 
-                let new_tree = replace_submodule_dir(&repo, &tree, &submodule_path, &subtree_id);
+                let new_tree = replace_submodule_dir(repo,
+                                                     &tree,
+                                                     submodule_path,
+                                                     prefix_path,
+                                                     subtree_id);
 
                 // In commits that used to update the submodule, add a parent pointing to
                 // appropriate commit in new submodule history
-                let mut parent_subtree_ids = HashSet::new();
-                for parent in commit.parents() {
-                    let parent_tree = parent.tree().expect("Couldn't obtain parent's tree");
-                    let parent_subdir_tree_id = parent_tree.get_path(submodule_path)
-                        .and_then(|x| Ok(x.id()));
-
-                    match parent_subdir_tree_id {
-                        Ok(id) => {
-                            parent_subtree_ids.insert(id);
-                            ()
-                        }
-                        Err(e) => {
-                            if e.code() == git2::ErrorCode::NotFound &&
-                               e.class() == git2::ErrorClass::Tree {
-                                continue;
-                            } else {
-                                panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                            };
-                        }
-                    }
-                }
+                let (parent_subtree_ids, first_parent_subtree_id) =
+                    parent_submodule_states(&commit, submodule_path);
 
                 // Here's a few pictures to help you understand how we figure out if current commit
                 // updated the submodule. If we draw a DAG and name submodule states, the following
@@ -544,8 +1610,10 @@ fn rewrite_repo_history(repo: &Repository,
                 // doesn't belong to the set of states in parents.
                 let submodule_updated: bool = !parent_subtree_ids.contains(&submodule_commit_id);
 
-                // Rewrite the parents if the submodule was updated
-                let parents = {
+                // Rewrite the parents if the submodule was updated. In flatten mode, submodule's
+                // history is left out, so no merges are created.
+                let submodule_parent_id = submodule_commit.id();
+                let mut parents = {
                     let mut p: Vec<Commit> = Vec::new();
                     for parent_id in commit.parent_ids() {
                         let actual_parent_id = old_id_to_new[&parent_id];
@@ -553,98 +1621,453 @@ fn rewrite_repo_history(repo: &Repository,
                             .expect("Couldn't find parent commit by its id");
                         p.push(parent);
                     }
+                    p
+                };
+
+                let old_submodule_commit_id = first_parent_subtree_id
+                    .map(|id| resolve_submodule_commit(&id, old_id_to_new, options));
+                // If the submodule moved back to a commit that is already reachable through one
+                // of the parents, merging it again would only clutter the history. Checking that
+                // means walking the history back to the merge base, so it's only done for commits
+                // that actually updated the submodule.
+                let already_merged = || {
+                    parents.iter().any(|p| {
+                        p.id() == submodule_parent_id ||
+                        repo.graph_descendant_of(p.id(), submodule_parent_id)
+                            .expect("Couldn't check if one commit descends from another")
+                    })
+                };
+                let merge_skipped = submodule_updated && mode != Mode::Flatten &&
+                                    already_merged();
+                let add_submodule_parent = submodule_updated && mode != Mode::Flatten &&
+                                           !merge_skipped;
+                if submodule_updated {
+                    summary.updates.push(SubmoduleUpdate {
+                        commit: oid,
+                        old: old_submodule_commit_id,
+                        new: resolved_submodule_commit_id,
+                        kind: classify_update(repo,
+                                              old_submodule_commit_id,
+                                              resolved_submodule_commit_id),
+                        merge_skipped,
+                    });
+                }
+
+                let mut message = commit.message_raw_bytes().to_vec();
+                // Parents are always rewritten before their children, so by now we know the new
+                // IDs of all the commits this one could possibly mention
+                if options.rewrite_messages {
+                    message = message::rewrite_commit_ids(repo, &message, old_id_to_new);
+                }
+                if options.annotate {
+                    message = message::append_trailers(&message,
+                                                       &[("Submerged-from", oid.to_string())]);
+                }
+
+                // If the commit changed something besides the submodule, these changes are
+                // committed on their own, and the submodule update is merged in by a separate
+                // commit. That way, they don't end up hidden in a merge. Root commits that add the
+                // submodule are always split, even if they don't contain anything else: otherwise
+                // submodule's commit would be their first parent, and `--first-parent` traversals
+                // would wander off into submodule's history.
+                let root_commit = parents.is_empty();
+                if (options.split_updates || root_commit) && add_submodule_parent {
+                    let main_tree = without_submodule_update(repo,
+                                                             &new_tree,
+                                                             prefix_path,
+                                                             parents.first());
+                    let changes_anything = match parents.first() {
+                        Some(parent) => parent.tree_id() != main_tree.id(),
+                        None => !main_tree.is_empty(),
+                    };
+
+                    if changes_anything || root_commit {
+                        let parent_ids = parents.iter().map(|p| p.id()).collect();
+                        let (mut main_commit, _) =
+                            NewCommit::based_on(&commit, main_tree.id(), parent_ids);
+                        main_commit.message = message;
+                        let main_commit_id = writer.write(repo, main_commit)?;
+                        commit_map.splits.insert(oid, main_commit_id);
+                        let main_commit = repo.find_commit(main_commit_id)
+                            .expect("Couldn't find the commit we just created");
+                        parents = vec![main_commit];
+
+                        message = format!("Update {} to {}\n", options.prefix, submodule_parent_id)
+                            .into_bytes();
+                        if options.annotate {
+                            message = message::append_trailers(&message,
+                                                               &[("Submerged-from",
+                                                                  oid.to_string())]);
+                        }
+                    }
+                }
 
-                    if submodule_updated {
-                        p.push(submodule_commit);
+                // With the submodule as the last parent, `--first-parent` traversals of the new
+                // history should visit exactly the same commits as in the old one
+                if add_submodule_parent {
+                    match options.parent_order {
+                        ParentOrder::First => parents.insert(0, submodule_commit),
+                        ParentOrder::Last => parents.push(submodule_commit),
                     }
+                }
 
-                    p
-                };
+                if options.annotate_updates && add_submodule_parent {
+                    let range = match old_submodule_commit_id {
+                        Some(old) => format!("{}..{}", old, resolved_submodule_commit_id),
+                        None => resolved_submodule_commit_id.to_string(),
+                    };
+                    message = message::append_trailers(&message, &[("Submodule-update", range)]);
+                }
 
-                let mut parents_refs: Vec<&Commit> = Vec::new();
-                for i in 0..parents.len() {
-                    parents_refs.push(&parents[i]);
+                if mode == Mode::Subtree && add_submodule_parent {
+                    message = append_subtree_metadata(&message,
+                                                      &options.prefix,
+                                                      &parents,
+                                                      &submodule_parent_id);
                 }
-                let new_commit_id = repo.commit(None,
-                            &commit.author(),
-                            &commit.committer(),
-                            &commit.message().expect("Couldn't retrieve commit's message"),
-                            &new_tree,
-                            &parents_refs[..])
-                    .expect("Failed to commit");
+
+                let parent_ids = parents.iter().map(|p| p.id()).collect();
+                let (mut new_commit, dropped_headers) =
+                    NewCommit::based_on(&commit, new_tree.id(), parent_ids);
+                summary.record_dropped_headers(oid, dropped_headers);
+                new_commit.message = message;
+                let new_commit_id = writer.write(repo, new_commit)?;
 
                 old_id_to_new.insert(oid, new_commit_id);
+                commit_map.commits.push((oid, new_commit_id));
             }
             Err(e) => eprintln!("Error walking the repo's history: {:?}", e),
         }
     }
 
-    let branches = repo.branches(Some(git2::BranchType::Local))
-        .expect("Couldn't obtain an iterator over local branches");
-    for maybe_branch in branches {
-        match maybe_branch {
-            Ok((branch, _)) => {
-                let mut reference = branch.into_reference();
-                let id = reference.peel(git2::ObjectType::Commit)
-                    .expect("Couldn't convert branch into a Commit")
-                    .id();
-                let new_id = old_id_to_new[&id];
-                reference.set_target(new_id, "git-submerge: moving to rewritten history")
-                    .expect("Couldn't move branch to rewritten history");
+    let mut ref_names: Vec<&String> = ref_targets.keys().collect();
+    ref_names.sort();
+
+    let mut updates = Vec::new();
+    for name in ref_names {
+        let old = ref_targets[name];
+        let new = if name.starts_with("refs/notes/") {
+            copy_notes(repo, name, old, old_id_to_new, &writer.notes_signature)
+        } else if name == refs::STASH_REF {
+            // The stash has to be rebuilt if any of its entries were rewritten, even if the latest
+            // one stayed the same
+            let new = new_ref_target(repo, old, old_id_to_new, options).unwrap_or(old);
+            let rewritten = refs::stash_entries(repo)
+                .iter()
+                .any(|id| old_id_to_new.get(id).is_some_and(|new_id| new_id != id));
+            if rewritten { Some(new) } else { None }
+        } else {
+            new_ref_target(repo, old, old_id_to_new, options)
+        };
+        if let Some(new) = new {
+            updates.push(RefUpdate {
+                name: name.clone(),
+                old,
+                new,
+            });
+        }
+    }
+    Ok(updates)
+}
+
+// Re-creates a commit that doesn't contain the submodule on top of its rewritten parents. Commits
+// made before the submodule was added have nothing to rewrite, so they're kept as they are; but
+// the ones made after it was removed descend from rewritten commits, and have to be rewritten too.
+fn carry_over_commit(repo: &Repository,
+                     commit: &Commit,
+                     old_id_to_new: &HashMap<Oid, Oid>,
+                     options: &Options,
+                     writer: &CommitWriter,
+                     summary: &mut Summary)
+                     -> Result<Oid, String> {
+    let parents: Vec<Oid> = commit.parent_ids().map(|id| old_id_to_new[&id]).collect();
+    if parents.iter().cloned().eq(commit.parent_ids()) {
+        return Ok(commit.id());
+    }
+
+    let (mut new_commit, dropped_headers) = NewCommit::based_on(commit, commit.tree_id(), parents);
+    summary.record_dropped_headers(commit.id(), dropped_headers);
+    if options.rewrite_messages {
+        new_commit.message = message::rewrite_commit_ids(repo, &new_commit.message, old_id_to_new);
+    }
+    if options.annotate {
+        new_commit.message = message::append_trailers(&new_commit.message,
+                                                      &[("Submerged-from",
+                                                         commit.id().to_string())]);
+    }
+    writer.write(repo, new_commit)
+}
+
+// Returns what the ref pointing at `target_id` should point at in the rewritten history, or None
+// if it doesn't have to be moved. Refs pointing right at commits (branches, lightweight tags and
+// such) are simply moved; annotated tags have to be re-created, since they contain the ID of the
+// object they point at.
+fn new_ref_target(repo: &Repository,
+                  target_id: Oid,
+                  old_id_to_new: &HashMap<Oid, Oid>,
+                  options: &Options)
+                  -> Option<Oid> {
+    match repo.find_tag(target_id) {
+        Ok(tag) => {
+            let new_target_id = match old_id_to_new.get(&tag.target_id()) {
+                Some(id) if *id != tag.target_id() => *id,
+                _ => return None,
+            };
+            let mut message = tag.message_bytes().unwrap_or(b"").to_vec();
+            if options.rewrite_messages {
+                message = message::rewrite_commit_ids(repo, &message, old_id_to_new);
+            }
+            Some(objects::write_tag(repo,
+                                    new_target_id,
+                                    tag.name_bytes(),
+                                    tag.tagger().as_ref(),
+                                    &message))
+        }
+        Err(_) => {
+            // `old_id_to_new` also maps some trees, but we're only interested in commits
+            match old_id_to_new.get(&target_id) {
+                Some(id) if *id != target_id && repo.find_commit(target_id).is_ok() => Some(*id),
+                _ => None,
+            }
+        }
+    }
+}
+
+// Notes are attached to object IDs, so rewritten commits have none. We copy them from the original
+// commits, like `git notes copy` does; the original notes are left in place.
+// libgit2 can only add notes by committing them to a ref, so we work on a temporary copy of
+// `notes_ref`, and return the commit it ended up at (or None if there was nothing to copy). The
+// ref itself is moved along with all the others.
+fn copy_notes(repo: &Repository,
+              notes_ref: &str,
+              notes_commit_id: Oid,
+              old_id_to_new: &HashMap<Oid, Oid>,
+              signature: &Signature)
+              -> Option<Oid> {
+    let temp_ref = "refs/git-submerge/notes";
+    repo.reference(temp_ref, notes_commit_id, true, "git-submerge: copying notes")
+        .expect("Couldn't create a temporary ref for notes");
+
+    let notes: Vec<(Oid, Oid)> = repo.notes(Some(temp_ref))
+        .unwrap_or_else(|_| panic!("Couldn't read notes from `{}'", notes_ref))
+        .filter_map(|note| note.ok())
+        .collect();
+    for (_, annotated_id) in notes {
+        let new_id = match old_id_to_new.get(&annotated_id) {
+            Some(id) if *id != annotated_id => *id,
+            _ => continue,
+        };
+        if repo.find_note(Some(temp_ref), new_id).is_ok() {
+            continue;
+        }
+
+        let note = repo.find_note(Some(temp_ref), annotated_id)
+            .unwrap_or_else(|_| panic!("Couldn't read a note on {}", annotated_id));
+        match note.message() {
+            Some(message) => {
+                repo.note(signature, signature, Some(temp_ref), new_id, message, false)
+                    .unwrap_or_else(|_| panic!("Couldn't copy a note to {}", new_id));
+            }
+            None => {
+                eprintln!("Skipping a note on {} in `{}', as it isn't valid UTF-8",
+                          annotated_id,
+                          notes_ref)
             }
-            Err(e) => eprintln!("Error walking the branches: {:?}", e),
         }
     }
+
+    let new_notes_commit_id = repo.refname_to_id(temp_ref)
+        .expect("Couldn't read back the temporary notes ref");
+    repo.find_reference(temp_ref)
+        .and_then(|mut reference| reference.delete())
+        .expect("Couldn't remove the temporary notes ref");
+
+    if new_notes_commit_id == notes_commit_id {
+        None
+    } else {
+        Some(new_notes_commit_id)
+    }
+}
+
+// `git subtree` looks for these trailers to find out which commits in the main repo correspond
+// to which commits of the subproject. A merge that has the subproject commit as its second parent
+// is what `git subtree merge --rejoin` creates, so we only record mainline and split IDs for such
+// merges; everything else just gets the directory.
+fn append_subtree_metadata(message: &[u8],
+                           submodule_dir: &str,
+                           parents: &[Commit],
+                           submodule_parent_id: &Oid)
+                           -> Vec<u8> {
+    let mut trailers = vec![("git-subtree-dir", String::from(submodule_dir))];
+    if parents.len() == 2 && parents[1].id() == *submodule_parent_id {
+        trailers.push(("git-subtree-mainline", parents[0].id().to_string()));
+        trailers.push(("git-subtree-split", submodule_parent_id.to_string()));
+    }
+    message::append_trailers(message, &trailers)
+}
+
+// Returns a copy of `new_tree` where the prefix directory is in the same state as in the (already
+// rewritten) `parent`, i.e. the tree with everything the commit changed except for the submodule
+fn without_submodule_update<'repo>(repo: &'repo Repository,
+                                   new_tree: &Tree,
+                                   prefix_path: &Path,
+                                   parent: Option<&Commit>)
+                                   -> Tree<'repo> {
+    let old_subtree = parent.and_then(|p| p.tree().ok())
+        .and_then(|t| t.get_path(prefix_path).ok())
+        .map(|te| (te.id(), te.filemode()));
+    let tree_id = match update_tree_entry(repo, Some(new_tree), prefix_path, old_subtree) {
+        Some(id) => id,
+        None => write_empty_tree(repo),
+    };
+    repo.find_tree(tree_id).expect("Couldn't read back the Tree we just wrote")
 }
 
 fn replace_submodule_dir<'repo>(repo: &'repo Repository,
                                 tree: &Tree,
                                 submodule_path: &Path,
-                                subtree_id: &Oid)
+                                prefix_path: &Path,
+                                subtree_id: Option<Oid>)
                                 -> Tree<'repo> {
-    let mut treebuilder = repo.treebuilder(Some(&tree))
+    let find_tree = |id| repo.find_tree(id).expect("Couldn't read back the Tree we just wrote");
+
+    let mut new_tree = update_tree_entry(repo, Some(tree), submodule_path, None)
+        .map(&find_tree);
+    // If the filters left nothing of the submodule, there's nothing to put in its place
+    if let Some(id) = subtree_id {
+        new_tree = update_tree_entry(repo, new_tree.as_ref(), prefix_path, Some((id, 0o040000)))
+            .map(&find_tree);
+    }
+    new_tree = update_tree_entry(repo, new_tree.as_ref(), Path::new(".gitmodules"), None)
+        .map(&find_tree);
+
+    match new_tree {
+        Some(tree) => tree,
+        None => find_tree(write_empty_tree(repo)),
+    }
+}
+
+fn write_empty_tree(repo: &Repository) -> Oid {
+    repo.treebuilder(None)
+        .and_then(|tb| tb.write())
+        .expect("Couldn't write an empty Tree")
+}
+
+// Puts an entry with given ID and filemode at `path` inside the `tree`, creating intermediate
+// directories as needed. If `entry` is None, the path is removed instead, along with directories
+// that became empty because of that. Returns the ID of the resulting tree, or None if it's empty.
+fn update_tree_entry(repo: &Repository,
+                     tree: Option<&Tree>,
+                     path: &Path,
+                     entry: Option<(Oid, i32)>)
+                     -> Option<Oid> {
+    let mut components = path.components();
+    let name = components.next()
+        .expect("Can't update an entry at an empty path")
+        .as_os_str();
+    let rest = components.as_path();
+
+    let mut treebuilder = repo.treebuilder(tree)
         .expect("Couldn't create TreeBuilder");
+    let exists = treebuilder.get(name)
+        .expect("Couldn't look up an entry in TreeBuilder")
+        .is_some();
 
-    treebuilder.remove(submodule_path)
-        .expect("Couldn't remove submodule path from TreeBuilder");
-    treebuilder.insert(submodule_path, *subtree_id, 0o040000)
-        .expect("Couldn't add submodule as a subdir to TreeBuilder");
+    let new_entry = if rest.as_os_str().is_empty() {
+        entry
+    } else {
+        let subtree = tree.and_then(|t| t.get_path(Path::new(name)).ok())
+            .and_then(|te| repo.find_tree(te.id()).ok());
+        update_tree_entry(repo, subtree.as_ref(), rest, entry).map(|id| (id, 0o040000))
+    };
+
+    match new_entry {
+        Some((id, filemode)) => {
+            treebuilder.insert(name, id, filemode)
+                .expect("Couldn't add an entry to TreeBuilder");
+        }
+        None => {
+            if exists {
+                treebuilder.remove(name)
+                    .expect("Couldn't remove an entry from TreeBuilder");
+            }
+        }
+    }
+
+    if treebuilder.is_empty() {
+        None
+    } else {
+        Some(treebuilder.write().expect("Couldn't write TreeBuilder into a Tree"))
+    }
+}
 
-    treebuilder.remove(".gitmodules")
-        .expect("Couldn't remove .gitmodules from TreeBuilder");
+// Brings a working directory, either the main one or a linked worktree, in line with its
+// rewritten HEAD. Returns the paths, relative to the working directory, of the files that were left
+// alone because they have local changes.
+fn update_workdir(repo: &Repository, state: &WorkdirState, options: &Options) -> Vec<String> {
+    let workdir = worktree_dir(repo);
+    let submodule_dir = &options.submodule_dir;
 
-    let new_tree_id = treebuilder.write()
-        .expect("Couldn't write TreeBuilder into a Tree");
-    let new_tree = repo.find_tree(new_tree_id)
-        .expect("Couldn't read back the Tree we just wrote");
+    // Working directories with and without submodules are pretty much
+    // the same, save for two files:
+    // - submodules have .git in their root directory;
+    // - there's .gitmodules in the root of the repo.
+    remove_dotgit_from_submodule(&workdir, submodule_dir);
+    if options.prefix != *submodule_dir {
+        move_submodule_dir(&workdir, submodule_dir, &options.prefix);
+    }
+    // Git used to think of submodule's directory as a file, because it was
+    // "opaque". We have to update the index in order for Git to realise
+    // that the submodule directory is *just* a directory now.
+    update_index(repo);
+    // The submodule might not have been checked out at the commit the main repo recorded, or
+    // the filters might have changed what's in its directory, so its files have to be checked out
+    // as well
+    let conflicts = checkout::sync_submodule_dir(repo, state, &options.prefix);
+    checkout::refresh_index(repo);
+    conflicts
+}
 
-    new_tree
+fn worktree_dir(repo: &Repository) -> PathBuf {
+    repo.workdir().expect("Repository doesn't have a working directory").to_owned()
 }
 
-fn remove_dotgit_from_submodule(submodule_dir: &str) {
-    let dotgit_path = String::from(submodule_dir) + "/.git";
-    std::fs::remove_file(&dotgit_path).expect(&format!("Couldn't remove {}", dotgit_path));
+// Linked worktrees often don't have the submodule initialized, in which case there's no .git
+fn remove_dotgit_from_submodule(workdir: &Path, submodule_dir: &str) {
+    let dotgit_path = workdir.join(submodule_dir).join(".git");
+    if dotgit_path.symlink_metadata().is_err() {
+        return;
+    }
+    std::fs::remove_file(&dotgit_path)
+        .unwrap_or_else(|_| panic!("Couldn't remove {}", dotgit_path.display()));
 }
 
-fn remove_gitmodules() {
-    let gitmodules_path = ".gitmodules";
-    std::fs::remove_file(&gitmodules_path).expect("Couldn't remove .gitmodules");
+// Moves submodule's working copy to the place where its files are now stored. We go through
+// a temporary directory because the prefix might be inside the submodule's directory.
+fn move_submodule_dir(workdir: &Path, submodule_dir: &str, prefix: &str) {
+    let submodule_path = workdir.join(submodule_dir);
+    if !submodule_path.is_dir() {
+        return;
+    }
+    let temp_dir = workdir.join(String::from(submodule_dir) + ".git-submerge-tmp");
+    std::fs::rename(&submodule_path, &temp_dir).unwrap_or_else(|_| {
+        panic!("Couldn't move {} to {}", submodule_path.display(), temp_dir.display())
+    });
+
+    let prefix_path = workdir.join(prefix);
+    if let Some(parent) = prefix_path.parent() {
+        std::fs::create_dir_all(parent)
+            .unwrap_or_else(|_| panic!("Couldn't create directory {}", parent.display()));
+    }
+    std::fs::rename(&temp_dir, &prefix_path).unwrap_or_else(|_| {
+        panic!("Couldn't move {} to {}", temp_dir.display(), prefix_path.display())
+    });
 }
 
-fn update_index(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>) {
-    let head = repo.head().expect("Couldn't obtain repo's HEAD");
-    let head_id = head.target().expect("Couldn't resolve repo's HEAD to a commit ID");
-    let updated_id = match old_id_to_new.get(&head_id) {
-        Some(id) => *id,
-        // If the ID wasn't found, it's okay - it means it's one of the new ones. It means HEAD
-        // was pointing at some branch, and since we've moved the branches at the end of repo's
-        // history rewrite, HEAD doesn't need updating
-        None => head_id,
-    };
-    let commit = repo.find_commit(updated_id)
-        .expect("Coudln't get the commit HEAD points at");
-    let tree = commit.tree()
-        .expect("Couldn't obtain commit's tree");
+fn update_index(repo: &Repository) {
+    let tree = repo.head()
+        .and_then(|head| head.peel_to_tree())
+        .expect("Couldn't obtain the tree of the rewritten HEAD");
     let mut index = repo.index()
         .expect("Couldn't obtain repo's index");
     index.read_tree(&tree)
@@ -652,3 +2075,55 @@ fn update_index(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>) {
     index.write()
         .expect("Couldn't write the index back to the repo");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset(time: Option<Time>) -> Option<(i64, i32)> {
+        time.map(|time| (time.seconds(), time.offset_minutes()))
+    }
+
+    #[test]
+    fn identity_without_time() {
+        let (name, email, time) = parse_identity("A U Thor <author@example.com>")
+            .expect("Should have been accepted");
+        assert_eq!(name, "A U Thor");
+        assert_eq!(email, "author@example.com");
+        assert!(time.is_none());
+    }
+
+    #[test]
+    fn identity_with_time() {
+        let (name, email, time) = parse_identity("  A U Thor  < author@example.com >  \
+                                                  1234567890 +0530")
+            .expect("Should have been accepted");
+        assert_eq!(name, "A U Thor");
+        assert_eq!(email, "author@example.com");
+        assert_eq!(offset(time), Some((1234567890, 330)));
+
+        let (_, _, time) = parse_identity("A <a@x> @1234567890 -0130")
+            .expect("Should have been accepted");
+        assert_eq!(offset(time), Some((1234567890, -90)));
+    }
+
+    #[test]
+    fn malformed_identities_are_rejected() {
+        for identity in &["A U Thor",
+                          "<a@x>",
+                          "A <>",
+                          "A >a@x<",
+                          "A <a@x",
+                          "A <a@x> 1234567890",
+                          "A <a@x> 1234567890 +0300 extra",
+                          "A <a@x> yesterday +0300",
+                          "A <a@x> 1234567890 0300",
+                          "A <a@x> 1234567890 +030",
+                          "A <a@x> 1234567890 +03:0",
+                          "A <a@x> 1234567890 *0300",
+                          "A <a@x> 123 \u{e9}123",
+                          "A <a@x> 123 +\u{e9}12"] {
+            assert!(parse_identity(identity).is_none(), "{} was accepted", identity);
+        }
+    }
+}
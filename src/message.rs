@@ -0,0 +1,296 @@
+use git2::{Oid, Repository};
+use std::collections::HashMap;
+use std::str;
+
+// Git doesn't abbreviate IDs to less than 7 characters, so anything shorter is most probably just
+// a number or a word
+const MIN_ABBREV_LEN: usize = 7;
+const FULL_ID_LEN: usize = 40;
+
+// Keys of the trailers git-submerge itself adds, with --annotate, --annotate-updates and --subtree
+pub const TRAILER_KEYS: &[&str] = &["Submerged-from",
+                                    "Submodule-commit",
+                                    "Submodule-update",
+                                    "git-subtree-dir",
+                                    "git-subtree-mainline",
+                                    "git-subtree-split"];
+
+// Replaces IDs of commits that were rewritten with their new IDs. Both full and abbreviated IDs
+// are replaced, as long as they unambiguously resolve to a commit we know about; abbreviated IDs
+// stay abbreviated to the same length. The message doesn't have to be valid UTF-8; IDs are ASCII
+// anyway, and non-ASCII bytes are treated as parts of words.
+pub fn rewrite_commit_ids(repo: &Repository,
+                          message: &[u8],
+                          old_id_to_new: &HashMap<Oid, Oid>)
+                          -> Vec<u8> {
+    let mut result = Vec::with_capacity(message.len());
+
+    let mut word_start = None;
+    for (i, b) in message.iter().cloned().chain(Some(b' ')).enumerate() {
+        if b.is_ascii_alphanumeric() || !b.is_ascii() {
+            if word_start.is_none() {
+                word_start = Some(i);
+            }
+            continue;
+        }
+
+        if let Some(start) = word_start.take() {
+            let word = &message[start..i];
+            let new_id = str::from_utf8(word)
+                .ok()
+                .and_then(|word| find_new_id(repo, word, old_id_to_new));
+            match new_id {
+                Some(new_id) => {
+                    result.extend_from_slice(&new_id.to_string().as_bytes()[..word.len()])
+                }
+                None => result.extend_from_slice(word),
+            }
+        }
+
+        if i < message.len() {
+            result.push(b);
+        }
+    }
+
+    result
+}
+
+// Adds "Key: value" lines to the end of the message. If the message already ends with a block of
+// trailers, the new ones are added to it; otherwise, they're separated by an empty line.
+pub fn append_trailers(message: &[u8], trailers: &[(&str, String)]) -> Vec<u8> {
+    let trimmed = trim_end(message);
+    let mut result = trimmed.to_vec();
+
+    let last_paragraph_start = trimmed.windows(2).rposition(|w| w == b"\n\n");
+    let ends_with_trailers = match last_paragraph_start {
+        Some(start) => trimmed[start + 2..].split(|&b| b == b'\n').all(is_trailer_line),
+        None => false,
+    };
+    if !trimmed.is_empty() {
+        result.extend_from_slice(if ends_with_trailers { b"\n" } else { b"\n\n" });
+    }
+
+    for &(key, ref value) in trailers {
+        result.extend_from_slice(key.as_bytes());
+        result.extend_from_slice(b": ");
+        result.extend_from_slice(value.as_bytes());
+        result.push(b'\n');
+    }
+
+    result
+}
+
+// Whether `rewritten` is `original` with some of the trailers listed in `TRAILER_KEYS` appended to
+// it by `append_trailers`. Only the end of the message is looked at, so trailers removed from or
+// added to the middle of it are still noticed.
+pub fn differs_only_in_trailers(original: &[u8], rewritten: &[u8]) -> bool {
+    if original == rewritten {
+        return true;
+    }
+
+    let mut end = trim_end(rewritten).len();
+    let mut stripped_any = false;
+    loop {
+        let start = rewritten[..end].iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos + 1);
+        let line = &rewritten[start..end];
+        let ours = is_trailer_line(line) &&
+                   TRAILER_KEYS.iter().any(|key| {
+            line.starts_with(key.as_bytes()) && line[key.len()..].starts_with(b": ")
+        });
+        if !ours {
+            break;
+        }
+        end = trim_end(&rewritten[..start]).len();
+        stripped_any = true;
+    }
+
+    stripped_any && &rewritten[..end] == trim_end(original)
+}
+
+fn trim_end(message: &[u8]) -> &[u8] {
+    let end = message.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |pos| pos + 1);
+    &message[..end]
+}
+
+fn is_trailer_line(line: &[u8]) -> bool {
+    match line.windows(2).position(|w| w == b": ") {
+        Some(pos) => {
+            pos > 0 && line[..pos].iter().all(|&b| b.is_ascii_alphanumeric() || b == b'-')
+        }
+        None => false,
+    }
+}
+
+fn find_new_id(repo: &Repository, word: &str, old_id_to_new: &HashMap<Oid, Oid>) -> Option<Oid> {
+    if word.len() < MIN_ABBREV_LEN || word.len() > FULL_ID_LEN {
+        return None;
+    }
+    if !word.chars().all(|c| c.is_ascii_hexdigit() && !c.is_uppercase()) {
+        return None;
+    }
+
+    let old_id = if word.len() == FULL_ID_LEN {
+        Oid::from_str(word).ok()
+    } else {
+        // revparse fails if the abbreviation is ambiguous, which is exactly what we want. It also
+        // resolves ref names, so we double-check that we got an object whose ID starts with
+        // the word.
+        repo.revparse_single(word)
+            .ok()
+            .map(|object| object.id())
+            .and_then(|id| if id.to_string().starts_with(word) { Some(id) } else { None })
+    };
+    let old_id = old_id?;
+
+    // `old_id_to_new` also maps some trees; we're only interested in commits
+    if repo.find_commit(old_id).is_err() {
+        return None;
+    }
+
+    match old_id_to_new.get(&old_id) {
+        Some(new_id) if *new_id != old_id => Some(*new_id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempRepo;
+
+    // Creates two commits, and pretends that the first one was rewritten as the second
+    fn rewritten_commit(temp: &TempRepo) -> (String, String, HashMap<Oid, Oid>) {
+        let old = temp.commit("original", &[]);
+        let new = temp.commit("rewritten", &[]);
+        let mut old_id_to_new = HashMap::new();
+        old_id_to_new.insert(old, new);
+        (old.to_string(), new.to_string(), old_id_to_new)
+    }
+
+    fn rewrite(temp: &TempRepo, message: &[u8], old_id_to_new: &HashMap<Oid, Oid>) -> Vec<u8> {
+        rewrite_commit_ids(&temp.repo, message, old_id_to_new)
+    }
+
+    #[test]
+    fn full_ids_are_replaced() {
+        let temp = TempRepo::new();
+        let (old, new, map) = rewritten_commit(&temp);
+        let message = format!("Revert {}.\n\nThis reverts commit {}.\n", old, old);
+        let expected = format!("Revert {}.\n\nThis reverts commit {}.\n", new, new);
+        assert_eq!(rewrite(&temp, message.as_bytes(), &map), expected.into_bytes());
+    }
+
+    #[test]
+    fn abbreviated_ids_keep_their_length() {
+        let temp = TempRepo::new();
+        let (old, new, map) = rewritten_commit(&temp);
+        let message = format!("Fixes {} (see also {})", &old[..7], &old[..12]);
+        let expected = format!("Fixes {} (see also {})", &new[..7], &new[..12]);
+        assert_eq!(rewrite(&temp, message.as_bytes(), &map), expected.into_bytes());
+    }
+
+    #[test]
+    fn uppercase_short_and_unknown_words_are_left_alone() {
+        let temp = TempRepo::new();
+        let (old, _, map) = rewritten_commit(&temp);
+        let message = format!("{} {} {}x deadbeef1234 facade",
+                              old.to_uppercase(),
+                              &old[..6],
+                              &old[..10]);
+        assert_eq!(rewrite(&temp, message.as_bytes(), &map), message.into_bytes());
+    }
+
+    #[test]
+    fn ids_of_commits_that_werent_rewritten_are_left_alone() {
+        let temp = TempRepo::new();
+        let id = temp.commit("kept", &[]);
+        let mut map = HashMap::new();
+        map.insert(id, id);
+        let message = format!("See {}", id);
+        assert_eq!(rewrite(&temp, message.as_bytes(), &map), message.into_bytes());
+    }
+
+    #[test]
+    fn ids_next_to_non_utf8_bytes() {
+        let temp = TempRepo::new();
+        let (old, new, map) = rewritten_commit(&temp);
+
+        // Separated by spaces, the ID is replaced, and the invalid bytes are kept as they are
+        let mut message = b"caf\xe9 ".to_vec();
+        message.extend_from_slice(&old.as_bytes()[..7]);
+        message.extend_from_slice(b" \xff\n");
+        let mut expected = b"caf\xe9 ".to_vec();
+        expected.extend_from_slice(&new.as_bytes()[..7]);
+        expected.extend_from_slice(b" \xff\n");
+        assert_eq!(rewrite(&temp, &message, &map), expected);
+
+        // Glued to a non-ASCII byte, it's part of a longer word
+        let mut message = b"\xe9".to_vec();
+        message.extend_from_slice(&old.as_bytes()[..7]);
+        assert_eq!(rewrite(&temp, &message, &map), message);
+    }
+
+    fn trailer() -> [(&'static str, String); 1] {
+        [("Submerged-from", String::from("1234567"))]
+    }
+
+    #[test]
+    fn trailers_start_a_new_paragraph() {
+        assert_eq!(append_trailers(b"Subject\n\nSome text.\n", &trailer()),
+                   b"Subject\n\nSome text.\n\nSubmerged-from: 1234567\n".to_vec());
+    }
+
+    #[test]
+    fn trailers_join_an_existing_trailer_block() {
+        assert_eq!(append_trailers(b"Subject\n\nText.\n\nSigned-off-by: A <a@x>\n\n", &trailer()),
+                   b"Subject\n\nText.\n\nSigned-off-by: A <a@x>\nSubmerged-from: 1234567\n"
+                       .to_vec());
+    }
+
+    #[test]
+    fn subject_that_looks_like_a_trailer_is_not_a_trailer_block() {
+        assert_eq!(append_trailers(b"Fix: handle empty input\n", &trailer()),
+                   b"Fix: handle empty input\n\nSubmerged-from: 1234567\n".to_vec());
+    }
+
+    #[test]
+    fn trailers_after_a_paragraph_that_is_not_all_trailers() {
+        assert_eq!(append_trailers(b"Subject\n\nSigned-off-by: A <a@x>\nand some text\n",
+                                   &trailer()),
+                   b"Subject\n\nSigned-off-by: A <a@x>\nand some text\n\nSubmerged-from: 1234567\n"
+                       .to_vec());
+    }
+
+    #[test]
+    fn trailers_keep_non_utf8_messages_intact() {
+        assert_eq!(append_trailers(b"caf\xe9\n\nsome \xff bytes\n", &trailer()),
+                   b"caf\xe9\n\nsome \xff bytes\n\nSubmerged-from: 1234567\n".to_vec());
+    }
+
+    #[test]
+    fn trailers_on_an_empty_message() {
+        assert_eq!(append_trailers(b"", &trailer()), b"Submerged-from: 1234567\n".to_vec());
+    }
+
+    #[test]
+    fn appended_trailers_are_the_only_difference() {
+        let trailers = [("Submerged-from", String::from("abc")),
+                        ("Submodule-update", String::from("def"))];
+        for original in [&b"Subject\n"[..], b"Subject\n\nSigned-off-by: A <a@b>\n", b"", b"\xff\n"]
+            .iter() {
+            let rewritten = append_trailers(original, &trailers);
+            assert!(differs_only_in_trailers(original, &rewritten));
+        }
+        assert!(differs_only_in_trailers(b"Subject\n", b"Subject\n"));
+    }
+
+    #[test]
+    fn other_differences_are_noticed() {
+        let trailer = [("Submerged-from", String::from("abc"))];
+        let rewritten = append_trailers(b"Subject\n\nBody\n", &trailer);
+        assert!(!differs_only_in_trailers(b"Subject\n\nOther body\n", &rewritten));
+        assert!(!differs_only_in_trailers(b"Subject\n", b"Subject\n\n"));
+        assert!(!differs_only_in_trailers(b"Subject\n", b"Subject\n\nSigned-off-by: A <a@b>\n"));
+        assert!(!differs_only_in_trailers(b"Subject\n\nSubmerged-from: abc\n", b"Subject\n"));
+    }
+}
@@ -0,0 +1,314 @@
+use git2::{Commit, Mailmap, ObjectType, Oid, Repository, Signature};
+use sign::Signer;
+
+// Headers that are tied to the commit's exact contents, and thus become invalid once the commit is
+// rewritten
+const INVALIDATED_HEADERS: [&str; 3] = ["gpgsig", "gpgsig-sha256", "mergetag"];
+
+// Everything that goes into a commit object. We write commits by hand rather than with
+// `Repository::commit`, because the latter only accepts UTF-8 messages, and can't store
+// the encoding header or any extra headers.
+pub struct NewCommit<'a> {
+    pub tree: Oid,
+    pub parents: Vec<Oid>,
+    pub author: Signature<'a>,
+    pub committer: Signature<'a>,
+    pub encoding: Option<Vec<u8>>,
+    // Headers we don't know about, each with its continuation lines, exactly as they were stored
+    pub extra_headers: Vec<Vec<u8>>,
+    pub message: Vec<u8>,
+}
+
+impl<'a> NewCommit<'a> {
+    // Copies authorship, message, encoding and extra headers from an existing commit. Headers
+    // listed in INVALIDATED_HEADERS are left out; their names are returned alongside the commit.
+    pub fn based_on(commit: &'a Commit,
+                    tree: Oid,
+                    parents: Vec<Oid>)
+                    -> (NewCommit<'a>, Vec<String>) {
+        let mut encoding = None;
+        let mut extra_headers = Vec::new();
+        let mut dropped_headers = Vec::new();
+
+        for header in split_headers(commit.raw_header_bytes()) {
+            let key_len = header.iter().position(|&b| b == b' ').unwrap_or(header.len());
+            let key = String::from_utf8_lossy(&header[..key_len]).into_owned();
+            match key.as_str() {
+                "tree" | "parent" | "author" | "committer" => {}
+                "encoding" => encoding = header.get(key_len + 1..).map(|v| v.to_vec()),
+                _ => {
+                    if INVALIDATED_HEADERS.contains(&key.as_str()) {
+                        if !dropped_headers.contains(&key) {
+                            dropped_headers.push(key);
+                        }
+                    } else {
+                        extra_headers.push(header);
+                    }
+                }
+            }
+        }
+
+        let new_commit = NewCommit {
+            tree,
+            parents,
+            author: commit.author(),
+            committer: commit.committer(),
+            encoding,
+            extra_headers,
+            message: commit.message_raw_bytes().to_vec(),
+        };
+
+        (new_commit, dropped_headers)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(format!("tree {}\n", self.tree).as_bytes());
+        for parent in &self.parents {
+            buf.extend_from_slice(format!("parent {}\n", parent).as_bytes());
+        }
+        buf.extend_from_slice(b"author ");
+        buf.extend_from_slice(&signature_to_bytes(&self.author));
+        buf.push(b'\n');
+        buf.extend_from_slice(b"committer ");
+        buf.extend_from_slice(&signature_to_bytes(&self.committer));
+        buf.push(b'\n');
+        if let Some(ref encoding) = self.encoding {
+            buf.extend_from_slice(b"encoding ");
+            buf.extend_from_slice(encoding);
+            buf.push(b'\n');
+        }
+        for header in &self.extra_headers {
+            buf.extend_from_slice(header);
+            buf.push(b'\n');
+        }
+        buf.push(b'\n');
+        buf.extend_from_slice(&self.message);
+
+        buf
+    }
+
+    // Writes the commit into the repo's object database. If a signer is given, the commit is
+    // signed, and the signature is stored in the `gpgsig` header, just like `git commit -S` does.
+    // Fails if the commit couldn't be signed.
+    pub fn write(&self, repo: &Repository, signer: Option<&Signer>) -> Result<Oid, String> {
+        let mut buf = self.to_bytes();
+
+        if let Some(signer) = signer {
+            let signature = signer.sign(&buf)?;
+            let header = signature_header(&signature);
+            let header_end = buf.windows(2)
+                .position(|w| w == b"\n\n")
+                .expect("Couldn't find the end of the commit's header") + 1;
+            buf.splice(header_end..header_end, header);
+        }
+
+        Ok(repo.odb()
+            .and_then(|odb| odb.write(ObjectType::Commit, &buf))
+            .expect("Failed to commit"))
+    }
+}
+
+// Things that are done to every commit we write, on top of whatever the rewrite itself changes
+pub struct CommitWriter {
+    pub signer: Option<Signer>,
+    pub mailmap: Option<Mailmap>,
+    // If set, replaces the committer of every commit
+    pub committer: Option<Signature<'static>>,
+    // Recorded as the author and committer of the commits that copy notes
+    pub notes_signature: Signature<'static>,
+}
+
+impl CommitWriter {
+    pub fn write(&self, repo: &Repository, mut commit: NewCommit) -> Result<Oid, String> {
+        if let Some(ref mailmap) = self.mailmap {
+            commit.author = resolve_identity(mailmap, commit.author);
+            commit.committer = resolve_identity(mailmap, commit.committer);
+        }
+
+        if let Some(ref committer) = self.committer {
+            commit.committer = committer.clone();
+        }
+
+        commit.write(repo, self.signer.as_ref())
+    }
+}
+
+// Replaces name and email according to the mailmap. The signature is only re-created if the
+// identity actually changed: libgit2 can't represent a "-0000" timezone, and we don't want to
+// change the timezone of commits that have nothing to do with the mailmap.
+fn resolve_identity<'a>(mailmap: &Mailmap, signature: Signature<'a>) -> Signature<'a> {
+    let resolved = mailmap.resolve_signature(&signature)
+        .expect("Couldn't resolve a signature through the mailmap");
+    if resolved.name_bytes() == signature.name_bytes() &&
+       resolved.email_bytes() == signature.email_bytes() {
+        signature
+    } else {
+        resolved
+    }
+}
+
+// Turns a multi-line signature into a `gpgsig` header, where each line but the first starts with
+// a space
+fn signature_header(signature: &[u8]) -> Vec<u8> {
+    let signature = match signature.last() {
+        Some(&b'\n') => &signature[..signature.len() - 1],
+        _ => signature,
+    };
+
+    let mut header = b"gpgsig ".to_vec();
+    for (i, line) in signature.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            header.extend_from_slice(b"\n ");
+        }
+        header.extend_from_slice(line);
+    }
+    header.push(b'\n');
+    header
+}
+
+// Splits the raw header into separate headers, keeping continuation lines (the ones starting with
+// a space) together with the header they belong to
+fn split_headers(raw_header: &[u8]) -> Vec<Vec<u8>> {
+    let mut headers: Vec<Vec<u8>> = Vec::new();
+    for line in raw_header.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+
+        if line[0] == b' ' {
+            if let Some(header) = headers.last_mut() {
+                header.push(b'\n');
+                header.extend_from_slice(line);
+                continue;
+            }
+        }
+
+        headers.push(line.to_vec());
+    }
+    headers
+}
+
+// Formats the signature the way Git stores it in objects: "Name <email> 1234567890 +0300"
+pub fn signature_to_bytes(signature: &Signature) -> Vec<u8> {
+    let when = signature.when();
+    let offset = when.offset_minutes().abs();
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(signature.name_bytes());
+    buf.extend_from_slice(b" <");
+    buf.extend_from_slice(signature.email_bytes());
+    buf.extend_from_slice(format!("> {} {}{:02}{:02}",
+                                  when.seconds(),
+                                  when.sign(),
+                                  offset / 60,
+                                  offset % 60)
+        .as_bytes());
+    buf
+}
+
+// Writes an annotated tag object pointing at a commit. Like with commits, `Repository::tag` would
+// only accept UTF-8 messages.
+pub fn write_tag(repo: &Repository,
+                 target: Oid,
+                 name: &[u8],
+                 tagger: Option<&Signature>,
+                 message: &[u8])
+                 -> Oid {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(format!("object {}\ntype commit\ntag ", target).as_bytes());
+    buf.extend_from_slice(name);
+    buf.push(b'\n');
+    if let Some(tagger) = tagger {
+        buf.extend_from_slice(b"tagger ");
+        buf.extend_from_slice(&signature_to_bytes(tagger));
+        buf.push(b'\n');
+    }
+    buf.push(b'\n');
+    buf.extend_from_slice(message);
+
+    repo.odb()
+        .and_then(|odb| odb.write(ObjectType::Tag, &buf))
+        .expect("Couldn't write a tag")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempRepo;
+
+    fn write_raw_commit(temp: &TempRepo, raw: &[u8]) -> Oid {
+        temp.repo
+            .odb()
+            .and_then(|odb| odb.write(ObjectType::Commit, raw))
+            .expect("Couldn't write a commit")
+    }
+
+    // Re-creates the commit from its parts, as the rewrite does when nothing has to change
+    fn round_trip(temp: &TempRepo, id: Oid) -> (Vec<u8>, Vec<String>) {
+        let commit = temp.repo.find_commit(id).expect("Couldn't find the commit");
+        let (new_commit, dropped_headers) =
+            NewCommit::based_on(&commit, commit.tree_id(), commit.parent_ids().collect());
+        (new_commit.to_bytes(), dropped_headers)
+    }
+
+    fn raw_object(temp: &TempRepo, id: Oid) -> Vec<u8> {
+        let odb = temp.repo.odb().expect("Couldn't open the object database");
+        let object = odb.read(id).expect("Couldn't read the object");
+        object.data().to_vec()
+    }
+
+    #[test]
+    fn plain_commit_is_reproduced_byte_for_byte() {
+        let temp = TempRepo::new();
+        let root = temp.commit("Root\n", &[]);
+        let id = temp.commit("Subject\n\nBody.\n", &[root]);
+
+        let (bytes, dropped_headers) = round_trip(&temp, id);
+        assert_eq!(bytes, raw_object(&temp, id));
+        assert!(dropped_headers.is_empty());
+    }
+
+    #[test]
+    fn encoding_extra_headers_and_non_utf8_message_are_reproduced() {
+        let temp = TempRepo::new();
+        let first = temp.commit("First\n", &[]);
+        let second = temp.commit("Second\n", &[]);
+        let tree = temp.repo.find_commit(first).expect("Couldn't find the commit").tree_id();
+
+        let mut raw = format!("tree {}\nparent {}\nparent {}\n", tree, first, second).into_bytes();
+        raw.extend_from_slice(b"author Ren\xe9 <rene@example.com> 1234567890 -0130\n");
+        raw.extend_from_slice(b"committer C O Mitter <c@example.com> 1234567899 +0545\n");
+        raw.extend_from_slice(b"encoding ISO-8859-1\n");
+        raw.extend_from_slice(b"x-custom first line\n second line\n third line\n");
+        raw.extend_from_slice(b"\nCaf\xe9\n\nNo trailing newline");
+        let id = write_raw_commit(&temp, &raw);
+
+        let (bytes, dropped_headers) = round_trip(&temp, id);
+        assert_eq!(bytes, raw);
+        assert!(dropped_headers.is_empty());
+    }
+
+    #[test]
+    fn signatures_are_dropped_and_reported() {
+        let temp = TempRepo::new();
+        let root = temp.commit("Root\n", &[]);
+        let tree = temp.repo.find_commit(root).expect("Couldn't find the commit").tree_id();
+
+        let header = format!("tree {}\nauthor A <a@x> 1234567890 +0000\ncommitter A <a@x> \
+                              1234567890 +0000\n",
+                             tree);
+        let mut signed = header.clone().into_bytes();
+        signed.extend_from_slice(b"gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n \
+                                   -----END PGP SIGNATURE-----\n");
+        signed.extend_from_slice(b"\nSigned\n");
+        let id = write_raw_commit(&temp, &signed);
+
+        let mut unsigned = header.into_bytes();
+        unsigned.extend_from_slice(b"\nSigned\n");
+        let (bytes, dropped_headers) = round_trip(&temp, id);
+        assert_eq!(bytes, unsigned);
+        assert_eq!(dropped_headers, vec![String::from("gpgsig")]);
+    }
+}
@@ -0,0 +1,186 @@
+use filter::RefFilter;
+use git2::{Oid, Reflog, Repository, Signature};
+use std::collections::HashMap;
+
+const REFLOG_MESSAGE: &str = "git-submerge: moving to rewritten history";
+
+// The stash keeps its entries in the reflog, so that's what has to be rewritten, rather than the
+// ref itself
+pub const STASH_REF: &str = "refs/stash";
+
+// A ref that has to be moved, along with the value it had when we started
+pub struct RefUpdate {
+    pub name: String,
+    pub old: Oid,
+    pub new: Oid,
+}
+
+// Reads what the selected refs point at. Symbolic refs, like refs/remotes/origin/HEAD, are left
+// out: they simply follow the refs they point at. Detached HEAD, on the other hand, is a ref of
+// its own, and it's always included, since the working directory is going to be updated to match
+// the rewritten history. The same goes for detached HEADs of linked worktrees, which are known to
+// libgit2 as worktrees/<name>/HEAD.
+pub fn read_ref_targets(repo: &Repository, refs: &RefFilter) -> HashMap<String, Oid> {
+    let mut result = HashMap::new();
+
+    let mut heads = vec![String::from("HEAD")];
+    let worktrees = repo.worktrees().expect("Couldn't obtain the list of worktrees");
+    heads.extend(worktrees.iter().flatten().map(|name| format!("worktrees/{}/HEAD", name)));
+    for name in heads {
+        if let Some(target) = direct_target(repo, &name) {
+            result.insert(name, target);
+        }
+    }
+
+    let names = refs.select(repo).expect("Couldn't obtain the list of refs");
+    for name in names {
+        let reference = repo.find_reference(&name)
+            .unwrap_or_else(|_| panic!("Couldn't find ref `{}'", name));
+        if let Some(target) = reference.target() {
+            result.insert(name, target);
+        }
+    }
+
+    result
+}
+
+// Moves all the refs at once. Each ref is locked and compared with the value it had when we
+// started; if someone moved it in the meantime (say, by committing or fetching), nothing is
+// updated at all.
+pub fn update_refs(repo: &Repository,
+                   updates: &[RefUpdate],
+                   old_id_to_new: &HashMap<Oid, Oid>)
+                   -> Result<(), String> {
+    if updates.is_empty() {
+        return Ok(());
+    }
+
+    // Kept in case the refs have to be rolled back
+    let original_stash_reflog = repo.reflog(STASH_REF).ok();
+
+    let mut transaction = repo.transaction()
+        .map_err(|e| format!("Couldn't start a transaction: {}", e.message()))?;
+    for update in updates {
+        transaction.lock_ref(&update.name)
+            .map_err(|e| format!("Couldn't lock `{}': {}", update.name, e.message()))?;
+
+        if direct_target(repo, &update.name) != Some(update.old) {
+            return Err(format!("`{}' was changed while git-submerge was running", update.name));
+        }
+
+        transaction.set_target(&update.name, update.new, None, REFLOG_MESSAGE)
+            .map_err(|e| format!("Couldn't move `{}': {}", update.name, e.message()))?;
+
+        // Every stash entry is moved to the rewritten history, otherwise older entries would
+        // keep the original history alive, and the latest one would show up twice
+        if update.name == STASH_REF {
+            let reflog = rewrite_reflog(repo, &update.name, old_id_to_new)
+                .map_err(|e| format!("Couldn't rewrite the stash: {}", e.message()))?;
+            transaction.set_reflog(&update.name, reflog)
+                .map_err(|e| format!("Couldn't rewrite the stash: {}", e.message()))?;
+        }
+    }
+
+    transaction.commit().map_err(|e| {
+        // libgit2 writes the refs one by one, so some of them might have been moved already
+        roll_back(repo, updates, original_stash_reflog);
+        format!("Couldn't move the refs: {}", e.message())
+    })
+}
+
+// Returns the IDs the stash entries point at, latest first
+pub fn stash_entries(repo: &Repository) -> Vec<Oid> {
+    repo.reflog(STASH_REF)
+        .map(|reflog| reflog.iter().map(|entry| entry.id_new()).collect())
+        .unwrap_or_default()
+}
+
+// Builds a copy of the ref's reflog where every entry points at the rewritten commit; entries
+// keep their committers, dates and messages
+fn rewrite_reflog(repo: &Repository,
+                  name: &str,
+                  old_id_to_new: &HashMap<Oid, Oid>)
+                  -> Result<Reflog, git2::Error> {
+    let mut reflog = repo.reflog(name)?;
+    let entries: Vec<(Oid, Signature<'static>, Option<String>)> = reflog.iter()
+        .map(|entry| {
+            (entry.id_new(), entry.committer().to_owned(), entry.message().map(String::from))
+        })
+        .collect();
+
+    while !reflog.is_empty() {
+        reflog.remove(0, false)?;
+    }
+    // Entries are listed latest first, but appended in the order they were made
+    for (id, committer, message) in entries.into_iter().rev() {
+        let id = old_id_to_new.get(&id).cloned().unwrap_or(id);
+        reflog.append(id, &committer, message.as_deref())?;
+    }
+    Ok(reflog)
+}
+
+// Puts back the refs that were already moved to the rewritten history
+fn roll_back(repo: &Repository, updates: &[RefUpdate], mut stash_reflog: Option<Reflog>) {
+    for update in updates {
+        if direct_target(repo, &update.name) != Some(update.new) {
+            continue;
+        }
+
+        let result = repo.reference_matching(&update.name,
+                                             update.old,
+                                             true,
+                                             update.new,
+                                             "git-submerge: rolling back");
+        if let Err(e) = result {
+            eprintln!("Couldn't move `{}' back to {}: {}", update.name, update.old, e.message());
+        }
+
+        if update.name == STASH_REF {
+            if let Some(mut reflog) = stash_reflog.take() {
+                if let Err(e) = reflog.write() {
+                    eprintln!("Couldn't restore the stash: {}", e.message());
+                }
+            }
+        }
+    }
+}
+
+// Unlike `Repository::refname_to_id`, doesn't follow symbolic refs, so that we notice if HEAD got
+// attached to a branch
+fn direct_target(repo: &Repository, name: &str) -> Option<Oid> {
+    repo.find_reference(name).ok().and_then(|reference| reference.target())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempRepo;
+
+    #[test]
+    fn every_stash_entry_is_rewritten() {
+        let temp = TempRepo::new();
+        let (older, latest) = (temp.commit("older", &[]), temp.commit("latest", &[]));
+        let new_older = temp.commit("new older", &[]);
+        let new_latest = temp.commit("new latest", &[]);
+        temp.repo.reference_ensure_log(STASH_REF).expect("Couldn't create the stash reflog");
+        temp.repo.reference(STASH_REF, older, true, "On master: older").expect("Couldn't stash");
+        temp.repo.reference(STASH_REF, latest, true, "On master: latest").expect("Couldn't stash");
+
+        let mut old_id_to_new = HashMap::new();
+        old_id_to_new.insert(older, new_older);
+        old_id_to_new.insert(latest, new_latest);
+        let updates = [RefUpdate {
+                           name: String::from(STASH_REF),
+                           old: latest,
+                           new: new_latest,
+                       }];
+        update_refs(&temp.repo, &updates, &old_id_to_new).expect("Couldn't update the refs");
+
+        assert_eq!(stash_entries(&temp.repo), vec![new_latest, new_older]);
+        let reflog = temp.repo.reflog(STASH_REF).expect("Couldn't read the stash reflog");
+        let messages: Vec<_> = reflog.iter().filter_map(|entry| entry.message().map(String::from))
+            .collect();
+        assert_eq!(messages, vec!["On master: latest", "On master: older"]);
+        assert_eq!(reflog.get(0).map(|entry| entry.id_old()), Some(new_older));
+    }
+}
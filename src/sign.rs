@@ -0,0 +1,169 @@
+use git2::Repository;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Kinds of signatures Git knows about; see `gpg.format` in git-config(1)
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+// Signs commits the same way `git commit -S` does, using the programs and the key configured for
+// Git
+pub struct Signer {
+    format: Format,
+    program: String,
+    key: String,
+    // ssh-keygen only accepts keys as files, so a literal public key from `user.signingkey` is
+    // written into this file, which is removed once we're done
+    key_file: Option<PathBuf>,
+}
+
+impl Signer {
+    pub fn from_config(repo: &Repository) -> Result<Signer, String> {
+        let mut signer = Signer::read_config(repo)?;
+
+        if let Some(literal_key) = signer.literal_key() {
+            let key_file = repo.path().join("git-submerge-signing-key.pub");
+            fs::write(&key_file, String::from(literal_key) + "\n")
+                .map_err(|e| format!("Couldn't write {}: {}", key_file.display(), e))?;
+            signer.key_file = Some(key_file);
+        }
+
+        // Better find out that the key doesn't work now than halfway through the rewrite
+        signer.sign(b"")?;
+
+        Ok(signer)
+    }
+
+    // Checks that signing is configured the way we understand, without writing any files or running
+    // the signing program
+    pub fn check_config(repo: &Repository) -> Result<(), String> {
+        Signer::read_config(repo).map(|_| ())
+    }
+
+    fn read_config(repo: &Repository) -> Result<Signer, String> {
+        let config = repo.config()
+            .map_err(|e| format!("Couldn't read Git config: {}", e.message()))?;
+
+        let format = match config.get_string("gpg.format") {
+            Err(_) => Format::OpenPgp,
+            Ok(ref format) if format == "openpgp" => Format::OpenPgp,
+            Ok(ref format) if format == "x509" => Format::X509,
+            Ok(ref format) if format == "ssh" => Format::Ssh,
+            Ok(format) => return Err(format!("Unsupported gpg.format `{}'", format)),
+        };
+
+        let program = match format {
+            Format::OpenPgp => {
+                config.get_string("gpg.openpgp.program")
+                    .or_else(|_| config.get_string("gpg.program"))
+                    .unwrap_or_else(|_| String::from("gpg"))
+            }
+            Format::X509 => {
+                config.get_string("gpg.x509.program").unwrap_or_else(|_| String::from("gpgsm"))
+            }
+            Format::Ssh => {
+                config.get_string("gpg.ssh.program").unwrap_or_else(|_| String::from("ssh-keygen"))
+            }
+        };
+
+        let key = match config.get_string("user.signingkey") {
+            Ok(key) => key,
+            Err(_) => {
+                if format == Format::Ssh {
+                    return Err(String::from("SSH signing requires user.signingkey to be set"));
+                }
+
+                // That's what Git does: GnuPG will look the key up by the committer's identity
+                let signature = repo.signature()
+                    .map_err(|e| format!("Couldn't obtain committer's identity: {}", e.message()))?;
+                format!("{} <{}>",
+                        String::from_utf8_lossy(signature.name_bytes()),
+                        String::from_utf8_lossy(signature.email_bytes()))
+            }
+        };
+
+        let mut signer = Signer {
+            format,
+            program,
+            key,
+            key_file: None,
+        };
+
+        if signer.format == Format::Ssh && signer.literal_key().is_none() &&
+           signer.key.starts_with("~/") {
+            if let Some(home) = env::var_os("HOME") {
+                let path = PathBuf::from(home).join(&signer.key[2..]);
+                signer.key = path.to_string_lossy().into_owned();
+            }
+        }
+
+        Ok(signer)
+    }
+
+    // With SSH, user.signingkey can be the public key itself rather than a path to it
+    fn literal_key(&self) -> Option<&str> {
+        if self.format != Format::Ssh {
+            None
+        } else if self.key.starts_with("key::") {
+            Some(&self.key["key::".len()..])
+        } else if self.key.starts_with("ssh-") {
+            Some(&self.key)
+        } else {
+            None
+        }
+    }
+
+    // Returns a detached signature for `data`, in the form it's stored in the `gpgsig` header
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut command = Command::new(&self.program);
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                command.arg("--status-fd=2").arg("-bsau").arg(&self.key);
+            }
+            Format::Ssh => {
+                command.arg("-Y").arg("sign").arg("-n").arg("git");
+                match self.key_file {
+                    // The private key is supposed to be in ssh-agent
+                    Some(ref key_file) => command.arg("-U").arg("-f").arg(key_file),
+                    None => command.arg("-f").arg(&self.key),
+                };
+            }
+        }
+
+        let mut child = command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Couldn't run `{}': {}", self.program, e))?;
+        child.stdin
+            .take()
+            .expect("Couldn't obtain signing program's stdin")
+            .write_all(data)
+            .map_err(|e| format!("Couldn't pass data to `{}': {}", self.program, e))?;
+        let output = child.wait_with_output()
+            .map_err(|e| format!("Couldn't get a signature from `{}': {}", self.program, e))?;
+
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(format!("`{}' failed to sign the data:\n{}",
+                               self.program,
+                               String::from_utf8_lossy(&output.stderr).trim_end()));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl Drop for Signer {
+    fn drop(&mut self) {
+        if let Some(ref key_file) = self.key_file {
+            let _ = fs::remove_file(key_file);
+        }
+    }
+}
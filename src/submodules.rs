@@ -0,0 +1,333 @@
+use filter::RefFilter;
+use {get_repo_revwalk, gitlink_at};
+use git2::{ObjectType, Oid, Repository, Revwalk};
+use refs;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+// What the main repo's history tells about a submodule that lived at some path
+pub struct SubmoduleHistory {
+    // Names the submodule had in .gitmodules, along with the URLs it had under each name
+    pub names: BTreeMap<String, BTreeSet<String>>,
+    // The first and the last commit that contained the submodule, in topological order
+    pub first_commit: Oid,
+    pub last_commit: Oid,
+    // Distinct submodule commits the main repo referenced, in the order they were first seen
+    pub commits: Vec<Oid>,
+}
+
+// Walks the history reachable from all refs and collects every submodule found in it, keyed by
+// its path
+pub fn scan_history(repo: &Repository) -> BTreeMap<String, SubmoduleHistory> {
+    let mut result: BTreeMap<String, SubmoduleHistory> = BTreeMap::new();
+    let mut seen_commits: HashMap<String, HashSet<Oid>> = HashMap::new();
+    let mut gitlinks_cache = HashMap::new();
+    let mut gitmodules_cache = HashMap::new();
+
+    for oid in history_revwalk(repo).filter_map(Result::ok) {
+        let commit = repo.find_commit(oid)
+            .unwrap_or_else(|_| panic!("Couldn't get a commit with ID {}", oid));
+        let tree = commit.tree()
+            .unwrap_or_else(|_| panic!("Couldn't obtain the tree of a commit with ID {}", oid));
+
+        let gitlinks = find_gitlinks(repo, tree.id(), &mut gitlinks_cache);
+        if gitlinks.is_empty() {
+            continue;
+        }
+
+        let gitmodules = match tree.get_path(Path::new(".gitmodules")) {
+            Ok(entry) => {
+                gitmodules_cache.entry(entry.id())
+                    .or_insert_with(|| {
+                        repo.find_blob(entry.id())
+                            .map(|blob| parse_gitmodules(blob.content()))
+                            .unwrap_or_default()
+                    })
+                    .clone()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        for (path, id) in gitlinks {
+            let history = result.entry(path.clone()).or_insert_with(|| {
+                SubmoduleHistory {
+                    names: BTreeMap::new(),
+                    first_commit: oid,
+                    last_commit: oid,
+                    commits: Vec::new(),
+                }
+            });
+            history.last_commit = oid;
+            if seen_commits.entry(path.clone()).or_default().insert(id) {
+                history.commits.push(id);
+            }
+            for entry in gitmodules.iter().filter(|entry| entry.path == path) {
+                let urls = history.names.entry(entry.name.clone()).or_default();
+                if let Some(ref url) = entry.url {
+                    urls.insert(url.clone());
+                }
+            }
+        }
+    }
+
+    result
+}
+
+// Returns the distinct submodule commits the main repo's history references at `path`, in the
+// order they were first seen
+pub fn gitlinks_at(repo: &Repository, path: &str) -> Vec<Oid> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for oid in history_revwalk(repo).filter_map(Result::ok) {
+        let gitlink = repo.find_commit(oid)
+            .and_then(|commit| commit.tree())
+            .ok()
+            .and_then(|tree| gitlink_at(&tree, Path::new(path)));
+        if let Some(gitlink) = gitlink {
+            if seen.insert(gitlink) {
+                result.push(gitlink);
+            }
+        }
+    }
+    result
+}
+
+// Finds where the submodule's history can be fetched from: the repo given by the user, the
+// submodule's working copy, or, if the submodule was removed, the repository Git keeps for it in
+// .git/modules
+pub fn repository_location(repo: &Repository,
+                           submodule_dir: &str,
+                           explicit: Option<&str>)
+                           -> Option<String> {
+    if let Some(location) = explicit {
+        return Some(String::from(location));
+    }
+
+    if repo.find_submodule(submodule_dir).and_then(|submodule| submodule.open()).is_ok() {
+        return Some(String::from("./") + submodule_dir);
+    }
+
+    let history = scan_history(repo);
+    let names = history.get(submodule_dir)?.names.keys();
+    names.map(|name| repo.path().join("modules").join(name))
+        .find(|path| Repository::open_bare(path).is_ok())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+// Opens the repositories that might have submodule's objects: its working copy, if the submodule
+// is still there, and whatever Git kept in .git/modules under the names it had
+pub fn open_repositories(repo: &Repository,
+                         path: &str,
+                         history: &SubmoduleHistory)
+                         -> Vec<Repository> {
+    let mut result = Vec::new();
+    if let Ok(submodule_repo) = repo.find_submodule(path).and_then(|s| s.open()) {
+        result.push(submodule_repo);
+    }
+    for name in history.names.keys() {
+        if let Ok(submodule_repo) = Repository::open_bare(repo.path().join("modules").join(name)) {
+            result.push(submodule_repo);
+        }
+    }
+    result
+}
+
+// Copies all objects from the repository at `location` into the main repo. That's what fetching
+// would do, but libgit2 refuses to fetch from a repository in .git/modules once the submodule's
+// working directory is gone: its core.worktree points at a directory that doesn't exist.
+pub fn copy_objects(repo: &Repository, location: &str) -> Result<(), git2::Error> {
+    let source_repo = Repository::open_bare(location)?;
+    let source = source_repo.odb()?;
+    let target = repo.odb()?;
+
+    let mut ids = Vec::new();
+    source.foreach(|id| {
+            ids.push(*id);
+            true
+        })?;
+    for id in ids {
+        if !target.exists(id) {
+            let object = source.read(id)?;
+            target.write(object.kind(), object.data())?;
+        }
+    }
+    Ok(())
+}
+
+// Makes submodule's objects visible through the main repo, without copying them. Unlike adding
+// the alternate to .git/objects/info/alternates, this only lasts while the repo is open.
+pub fn borrow_objects(repo: &Repository,
+                      submodule_dir: &str,
+                      submodule_repo: Option<&str>)
+                      -> Result<(), git2::Error> {
+    let location = repository_location(repo, submodule_dir, submodule_repo)
+        .ok_or_else(|| git2::Error::from_str("submodule's repository is missing"))?;
+    let submodule_repo = Repository::open(&location).or_else(|_| Repository::open_bare(&location))?;
+    let objects_dir = submodule_repo.path().join("objects");
+    repo.odb()?.add_disk_alternate(&objects_dir.to_string_lossy())?;
+    // At least one of the submodule commits the history refers to should be there, otherwise
+    // we're looking at the wrong repository
+    if gitlinks_at(repo, submodule_dir).iter().any(|id| repo.find_commit(*id).is_ok()) {
+        Ok(())
+    } else {
+        Err(git2::Error::from_str("submodule's commits are missing"))
+    }
+}
+
+pub fn history_revwalk<'repo>(repo: &'repo Repository) -> Revwalk<'repo> {
+    let ref_targets = refs::read_ref_targets(repo, &RefFilter::new(&[], &[]));
+    get_repo_revwalk(repo, &ref_targets)
+}
+
+// Returns the paths and IDs of all gitlinks in the tree. Most commits share most of their trees,
+// so results are cached.
+fn find_gitlinks(repo: &Repository,
+                 tree_id: Oid,
+                 cache: &mut HashMap<Oid, Vec<(String, Oid)>>)
+                 -> Vec<(String, Oid)> {
+    if let Some(gitlinks) = cache.get(&tree_id) {
+        return gitlinks.clone();
+    }
+
+    let tree = repo.find_tree(tree_id)
+        .unwrap_or_else(|_| panic!("Couldn't find tree {}", tree_id));
+    let mut result = Vec::new();
+    for entry in tree.iter() {
+        let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+        if entry.filemode() == 0o160000 {
+            result.push((name, entry.id()));
+        } else if entry.kind() == Some(ObjectType::Tree) {
+            for (path, id) in find_gitlinks(repo, entry.id(), cache) {
+                result.push((format!("{}/{}", name, path), id));
+            }
+        }
+    }
+
+    cache.insert(tree_id, result.clone());
+    result
+}
+
+#[derive(Clone)]
+struct GitmodulesEntry {
+    name: String,
+    path: String,
+    url: Option<String>,
+}
+
+// A bare-bones parser for .gitmodules, which is in git-config(1) format. We can't use libgit2's
+// parser without writing the blob out into a file.
+fn parse_gitmodules(contents: &[u8]) -> Vec<GitmodulesEntry> {
+    let mut result = Vec::new();
+    let mut current: Option<(String, Option<String>, Option<String>)> = None;
+
+    let mut finish = |current: Option<(String, Option<String>, Option<String>)>| {
+        if let Some((name, Some(path), url)) = current {
+            result.push(GitmodulesEntry { name, path, url });
+        }
+    };
+
+    for line in String::from_utf8_lossy(contents).lines() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            finish(current.take());
+            let section = line.trim_start_matches('[').trim_end_matches(']').trim();
+            if let Some(name) = section.strip_prefix("submodule") {
+                let name = name.trim().trim_matches('"');
+                current = Some((String::from(name), None, None));
+            }
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = match parts.next() {
+            Some(value) => String::from(value.trim().trim_matches('"')),
+            None => continue,
+        };
+        if let Some((_, ref mut path, ref mut url)) = current {
+            match key.as_str() {
+                "path" => *path = Some(value),
+                "url" => *url = Some(value),
+                _ => {}
+            }
+        }
+    }
+    finish(current);
+
+    result
+}
+
+// Cuts off a `#` or `;` comment, unless it's inside double quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Vec<(String, String, Option<String>)> {
+        parse_gitmodules(contents.as_bytes())
+            .into_iter()
+            .map(|entry| (entry.name, entry.path, entry.url))
+            .collect()
+    }
+
+    fn entry(name: &str, path: &str, url: Option<&str>) -> (String, String, Option<String>) {
+        (String::from(name), String::from(path), url.map(String::from))
+    }
+
+    #[test]
+    fn quoted_section_names() {
+        let contents = "[submodule \"lib/json\"]\n\tpath = ext/json\n\turl = ../json.git\n\
+                        [submodule \"with spaces\"]\n\tpath = \"a b\"\n";
+        assert_eq!(parse(contents),
+                   vec![entry("lib/json", "ext/json", Some("../json.git")),
+                        entry("with spaces", "a b", None)]);
+    }
+
+    #[test]
+    fn submodules_without_path_are_skipped() {
+        let contents = "[submodule \"nowhere\"]\n\turl = ../a.git\n\
+                        [submodule \"b\"]\n\tpath = b\n";
+        assert_eq!(parse(contents), vec![entry("b", "b", None)]);
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let contents = "# [submodule \"commented\"]\n\
+                        [submodule \"a\"] ; the only one\n\
+                        \t# path = wrong\n\
+                        \t; path = wrong\n\
+                        \tpath = a # trailing comment\n\
+                        \turl = \"https://example.com/a#b\" ; quoted hash\n";
+        assert_eq!(parse(contents),
+                   vec![entry("a", "a", Some("https://example.com/a#b"))]);
+    }
+
+    #[test]
+    fn keys_are_case_insensitive() {
+        let contents = "[submodule \"a\"]\n\tPath = a\n\tURL = ../a.git\n";
+        assert_eq!(parse(contents), vec![entry("a", "a", Some("../a.git"))]);
+    }
+
+    #[test]
+    fn other_sections_are_ignored() {
+        let contents = "[core]\n\tpath = nope\n[submodule \"a\"]\n\tpath = a\n";
+        assert_eq!(parse(contents), vec![entry("a", "a", None)]);
+    }
+}
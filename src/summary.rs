@@ -0,0 +1,108 @@
+use git2::Oid;
+
+// How the submodule's state changed between a commit and its first parent
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpdateKind {
+    // The submodule didn't exist in the parent
+    Added,
+    // The new state is a descendant of the old one
+    FastForward,
+    // The new state is an ancestor of the old one
+    Rollback,
+    // Neither state is an ancestor of the other
+    NonFastForward,
+}
+
+pub struct SubmoduleUpdate {
+    // ID of the main repo's commit (as it was before the rewrite)
+    pub commit: Oid,
+    pub old: Option<Oid>,
+    pub new: Oid,
+    pub kind: UpdateKind,
+    // Whether the submodule commit was already reachable from the rewritten parents, so the commit
+    // didn't become a merge
+    pub merge_skipped: bool,
+}
+
+// Things the user should know about once the rewrite is over
+pub struct Summary {
+    pub updates: Vec<SubmoduleUpdate>,
+    // Original commits whose headers couldn't be carried over to the rewritten commit, along with
+    // the names of those headers
+    pub dropped_headers: Vec<(Oid, Vec<String>)>,
+    // Original commits that were signed; the signatures can't survive the rewrite
+    pub lost_signatures: Vec<Oid>,
+    // Whether the rewritten commits were signed with user's own key
+    pub resigned: bool,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary {
+            updates: Vec::new(),
+            dropped_headers: Vec::new(),
+            lost_signatures: Vec::new(),
+            resigned: false,
+        }
+    }
+
+    pub fn record_dropped_headers(&mut self, commit: Oid, mut headers: Vec<String>) {
+        let signature_headers = ["gpgsig", "gpgsig-sha256"];
+        if headers.iter().any(|h| signature_headers.contains(&h.as_str())) {
+            self.lost_signatures.push(commit);
+            headers.retain(|h| !signature_headers.contains(&h.as_str()));
+        }
+
+        if !headers.is_empty() {
+            self.dropped_headers.push((commit, headers));
+        }
+    }
+
+    pub fn print(&self) {
+        self.print_updates(|u| u.kind == UpdateKind::Rollback && u.merge_skipped,
+                           "The following commits rolled the submodule back to a commit that \
+                            was already merged; no merges were created for them:");
+        self.print_updates(|u| u.kind == UpdateKind::Rollback && !u.merge_skipped,
+                           "The following commits rolled the submodule back to an older \
+                            commit:");
+        self.print_updates(|u| u.kind == UpdateKind::NonFastForward,
+                           "The following commits moved the submodule to a commit that doesn't \
+                            descend from the previous one:");
+
+        if !self.lost_signatures.is_empty() {
+            if self.resigned {
+                println!("\nThe following commits were signed; their rewritten versions are \
+                          signed with your key instead:\n");
+            } else {
+                println!("\nThe following commits were signed, but their rewritten versions \
+                          aren't; use --sign to sign them with your key:\n");
+            }
+            for commit in &self.lost_signatures {
+                println!("{}", commit);
+            }
+        }
+
+        if !self.dropped_headers.is_empty() {
+            println!("\nThe following commits had headers that are only valid for the original \
+                      commit, so they were dropped:\n");
+            for (commit, headers) in &self.dropped_headers {
+                println!("{}: {}", commit, headers.join(", "));
+            }
+        }
+    }
+
+    fn print_updates<F: Fn(&SubmoduleUpdate) -> bool>(&self, selected: F, header: &str) {
+        let updates: Vec<&SubmoduleUpdate> = self.updates.iter().filter(|u| selected(u)).collect();
+        if updates.is_empty() {
+            return;
+        }
+
+        println!("\n{}\n", header);
+        for update in updates {
+            match update.old {
+                Some(old) => println!("{}: {} -> {}", update.commit, old, update.new),
+                None => println!("{}: {}", update.commit, update.new),
+            }
+        }
+    }
+}
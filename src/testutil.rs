@@ -0,0 +1,50 @@
+use git2::{Oid, Repository, Signature, Time};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A scratch repository in the temporary directory; it's removed once the test is done with it
+pub struct TempRepo {
+    pub path: PathBuf,
+    pub repo: Repository,
+}
+
+impl TempRepo {
+    pub fn new() -> TempRepo {
+        let path = env::temp_dir().join(format!("git-submerge-test-{}-{}",
+                                                process::id(),
+                                                NEXT_ID.fetch_add(1, Ordering::SeqCst)));
+        let repo = Repository::init(&path)
+            .unwrap_or_else(|_| panic!("Couldn't create a repo at {}", path.display()));
+        TempRepo { path, repo }
+    }
+
+    // Commits an empty tree on top of `parents`; commits with different messages get different IDs
+    pub fn commit(&self, message: &str, parents: &[Oid]) -> Oid {
+        let when = Time::new(1234567890, 180);
+        let signature = Signature::new("A U Thor", "author@example.com", &when)
+            .expect("Couldn't create a signature");
+        let tree_id = self.repo
+            .treebuilder(None)
+            .and_then(|builder| builder.write())
+            .expect("Couldn't write an empty tree");
+        let tree = self.repo.find_tree(tree_id).expect("Couldn't find the empty tree");
+        let parents: Vec<_> = parents.iter()
+            .map(|id| self.repo.find_commit(*id).expect("Couldn't find the parent"))
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        self.repo
+            .commit(None, &signature, &signature, message, &tree, &parents)
+            .expect("Couldn't commit")
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
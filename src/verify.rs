@@ -0,0 +1,482 @@
+use commitmap::CommitMap;
+use filter::{PathFilter, bytes_to_path};
+use git2::{Commit, Index, ObjectType, Oid, Repository, Signature, Tree, TreeEntry};
+use gitlink_at;
+use message;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+// A file as stored in a tree: its path, mode and blob ID
+type FileEntry = (Vec<u8>, u32, Oid);
+
+// Checks every original commit from the map against its rewritten counterpart. The tree should be
+// the same, except that the gitlink is replaced by the submodule's tree (after the filters) and
+// .gitmodules is gone; authorship and the message should be the same, unless the rewrite was told
+// to change them (if it only replaced commit IDs or added trailers, the rest of the message should
+// still be the same); and the parents should be the rewritten parents, plus maybe the submodule
+// commit the rewrite merged in. Rewritten submodule commits are checked too: they should only
+// contain the submodule's files, moved into the prefix directory. The expected trees are computed
+// from the original ones, and nothing is written into the repository. Returns descriptions of the
+// mismatches.
+pub fn verify(repo: &Repository, map: &CommitMap) -> Vec<String> {
+    let submodule_path = Path::new(&map.submodule_dir);
+    let prefix_path = Path::new(&map.prefix);
+    let filter = match map.filter() {
+        Ok(filter) => filter,
+        Err(e) => {
+            return vec![format!("The filters in the commit map are invalid: {}", e.message())]
+        }
+    };
+    let commits: HashMap<Oid, Oid> = map.commits.iter().cloned().collect();
+    let submodule: HashMap<Oid, Oid> = map.submodule.iter().cloned().collect();
+    let old_id_to_new: HashMap<Oid, Oid> =
+        map.submodule.iter().chain(&map.commits).cloned().collect();
+    let changed = |what: &str| map.changed.iter().any(|c| c == what);
+
+    let mut mismatches = Vec::new();
+    let mut subtree_filter = SubtreeFilter::new(&filter);
+
+    for &(old, new) in map.submodule.iter().filter(|&&(old, new)| old != new) {
+        let (original, rewritten) = match find_pair(repo, old, new, &mut mismatches) {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let tree = commit_tree(&original);
+        let rewritten_tree = commit_tree(&rewritten);
+        if let Some(problem) = compare_trees(repo,
+                                             None,
+                                             &rewritten_tree,
+                                             &[],
+                                             prefix_path,
+                                             &tree,
+                                             &mut subtree_filter) {
+            mismatches.push(format!("submodule commit {}: {} in {}", old, problem, new));
+        }
+    }
+
+    for &(old, new) in &map.commits {
+        let (original, rewritten) = match find_pair(repo, old, new, &mut mismatches) {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        let tree = commit_tree(&original);
+        let rewritten_tree = commit_tree(&rewritten);
+        let gitlink = gitlink_at(&tree, submodule_path);
+
+        let mut submodule_parent = None;
+        let problem = match gitlink {
+            None => {
+                if rewritten_tree.id() == tree.id() {
+                    None
+                } else {
+                    Some(format!("the tree is {}, expected {}", rewritten_tree.id(), tree.id()))
+                }
+            }
+            Some(gitlink) => {
+                let used = match map.gitlinks.get(&gitlink) {
+                    Some(used) => *used,
+                    None => {
+                        mismatches.push(format!("{}: the commit map doesn't say what replaced \
+                                                 submodule commit {}",
+                                                old,
+                                                gitlink));
+                        continue;
+                    }
+                };
+                let used_tree = match repo.find_commit(used) {
+                    Ok(commit) => commit_tree(&commit),
+                    Err(_) => {
+                        mismatches.push(format!("{}: submodule commit {} is missing", old, used));
+                        continue;
+                    }
+                };
+                submodule_parent = submodule.get(&used).cloned();
+
+                compare_trees(repo,
+                              Some(&tree),
+                              &rewritten_tree,
+                              &[submodule_path, Path::new(".gitmodules")],
+                              prefix_path,
+                              &used_tree,
+                              &mut subtree_filter)
+            }
+        };
+        if let Some(problem) = problem {
+            mismatches.push(format!("{}: {} in {}", old, problem, new));
+        }
+
+        // With --split-updates, the original metadata and parents went to a separate commit, and
+        // the merge only has it as its parent
+        let split = map.splits.get(&old).map(|id| {
+            repo.find_commit(*id)
+                .unwrap_or_else(|_| panic!("Couldn't find the split commit {}", id))
+        });
+        let carrier = split.as_ref().unwrap_or(&rewritten);
+
+        if !changed("authors") && !same_signature(&original.author(), &carrier.author()) {
+            mismatches.push(format!("{}: the author of {} is different", old, carrier.id()));
+        }
+        if !changed("committers") && !same_signature(&original.committer(), &carrier.committer()) {
+            mismatches.push(format!("{}: the committer of {} is different", old, carrier.id()));
+        }
+        let mut expected_message = original.message_raw_bytes().to_vec();
+        if changed("messages") {
+            expected_message =
+                message::rewrite_commit_ids(repo, &expected_message, &old_id_to_new);
+        }
+        let same_message = if changed("trailers") {
+            message::differs_only_in_trailers(&expected_message, carrier.message_raw_bytes())
+        } else {
+            expected_message.as_slice() == carrier.message_raw_bytes()
+        };
+        if !same_message || original.message_encoding() != carrier.message_encoding() {
+            mismatches.push(format!("{}: the message of {} is different", old, carrier.id()));
+        }
+
+        let mut expected_parents = Vec::new();
+        for parent_id in original.parent_ids() {
+            match commits.get(&parent_id) {
+                Some(id) => expected_parents.push(*id),
+                None => {
+                    mismatches.push(format!("{}: parent {} isn't in the commit map",
+                                            old,
+                                            parent_id));
+                }
+            }
+        }
+        if let Some(ref split) = split {
+            if split.parent_ids().collect::<Vec<_>>() != expected_parents {
+                mismatches.push(format!("{}: the parents of {} are {}, expected {}",
+                                        old,
+                                        split.id(),
+                                        list_ids(split.parent_ids()),
+                                        list_ids(expected_parents.iter().cloned())));
+            }
+            expected_parents = vec![split.id()];
+        }
+
+        let mut actual_parents: Vec<Oid> = rewritten.parent_ids().collect();
+        if actual_parents.len() == expected_parents.len() + 1 {
+            if let Some(position) = actual_parents.iter()
+                .position(|id| Some(*id) == submodule_parent) {
+                actual_parents.remove(position);
+            }
+        }
+        if actual_parents != expected_parents {
+            mismatches.push(format!("{}: the parents of {} are {}, expected {}",
+                                    old,
+                                    new,
+                                    list_ids(rewritten.parent_ids()),
+                                    list_ids(expected_parents.iter().cloned())));
+        }
+    }
+
+    mismatches
+}
+
+fn find_pair<'repo>(repo: &'repo Repository,
+                    old: Oid,
+                    new: Oid,
+                    mismatches: &mut Vec<String>)
+                    -> Option<(Commit<'repo>, Commit<'repo>)> {
+    match (repo.find_commit(old), repo.find_commit(new)) {
+        (Ok(original), Ok(rewritten)) => Some((original, rewritten)),
+        (Err(_), _) => {
+            mismatches.push(format!("{}: the original commit is missing", old));
+            None
+        }
+        (_, Err(_)) => {
+            mismatches.push(format!("{}: the rewritten commit {} is missing", old, new));
+            None
+        }
+    }
+}
+
+fn commit_tree<'repo>(commit: &Commit<'repo>) -> Tree<'repo> {
+    commit.tree()
+        .unwrap_or_else(|_| panic!("Couldn't obtain the tree of a commit with ID {}", commit.id()))
+}
+
+// Applies the filters to submodule trees. Remembers which trees the filters leave nothing of, and
+// which pairs of (submodule tree, prefix directory) were already compared, since most commits share
+// them with their parents.
+struct SubtreeFilter<'f> {
+    filter: &'f PathFilter,
+    empty: HashMap<Oid, bool>,
+    matching: HashMap<(Oid, Oid), bool>,
+}
+
+impl<'f> SubtreeFilter<'f> {
+    fn new(filter: &'f PathFilter) -> SubtreeFilter<'f> {
+        SubtreeFilter {
+            filter,
+            empty: HashMap::new(),
+            matching: HashMap::new(),
+        }
+    }
+}
+
+// Checks that `rewritten` is the `original` tree (None if the rewritten commit should only contain
+// the submodule) with the `removed` paths gone and the `submodule_tree`, after the filters, put at
+// `prefix_path`. If the filters leave nothing of the submodule, whatever the original had at the
+// prefix should still be there. Returns a description of the first difference.
+fn compare_trees(repo: &Repository,
+                 original: Option<&Tree>,
+                 rewritten: &Tree,
+                 removed: &[&Path],
+                 prefix_path: &Path,
+                 submodule_tree: &Tree,
+                 subtree_filter: &mut SubtreeFilter)
+                 -> Option<String> {
+    let filter = subtree_filter.filter;
+    let leaves_nothing = *subtree_filter.empty
+        .entry(submodule_tree.id())
+        .or_insert_with(|| !filter.is_empty() && filtered_files(submodule_tree, filter).is_empty());
+    let prefix = if leaves_nothing { None } else { Some(prefix_path) };
+
+    if let Some(path) = find_difference(repo, original, Some(rewritten), Path::new(""), removed,
+                                        prefix) {
+        return Some(format!("{} is different", path.display()));
+    }
+    if leaves_nothing {
+        return None;
+    }
+
+    let subtree_id = match rewritten.get_path(prefix_path) {
+        Ok(ref entry) if entry.kind() == Some(ObjectType::Tree) => entry.id(),
+        _ => return Some(format!("{} isn't a directory", prefix_path.display())),
+    };
+    let matches = if filter.is_empty() {
+        subtree_id == submodule_tree.id()
+    } else {
+        *subtree_filter.matching.entry((submodule_tree.id(), subtree_id)).or_insert_with(|| {
+            let subtree = repo.find_tree(subtree_id)
+                .unwrap_or_else(|_| panic!("Couldn't find the tree {}", subtree_id));
+            filtered_files(submodule_tree, filter) == files(&subtree)
+        })
+    };
+    if matches {
+        None
+    } else {
+        Some(format!("{} doesn't match submodule tree {}",
+                     prefix_path.display(),
+                     submodule_tree.id()))
+    }
+}
+
+// Walks the trees down to the paths the rewrite changed, comparing everything else entry by entry.
+// `prefix` is skipped, since it's checked separately. Returns the first path that's different.
+fn find_difference(repo: &Repository,
+                   original: Option<&Tree>,
+                   rewritten: Option<&Tree>,
+                   dir: &Path,
+                   removed: &[&Path],
+                   prefix: Option<&Path>)
+                   -> Option<PathBuf> {
+    let mut names = BTreeSet::new();
+    for tree in original.iter().chain(rewritten.iter()) {
+        names.extend(tree.iter().map(|entry| entry.name_bytes().to_vec()));
+    }
+
+    for name in names {
+        let path = dir.join(bytes_to_path(&name));
+        if Some(path.as_path()) == prefix {
+            continue;
+        }
+
+        let old = original.and_then(|tree| tree.get_name_bytes(&name));
+        let new = rewritten.and_then(|tree| tree.get_name_bytes(&name));
+        let leads_to = |other: &Path| other != path && other.starts_with(&path);
+        if prefix.is_some_and(&leads_to) || removed.iter().any(|other| leads_to(other)) {
+            if new.as_ref().is_some_and(|entry| entry.kind() != Some(ObjectType::Tree)) {
+                return Some(path);
+            }
+            let old_subtree = as_tree(repo, old);
+            let new_subtree = as_tree(repo, new);
+            let difference = find_difference(repo,
+                                             old_subtree.as_ref(),
+                                             new_subtree.as_ref(),
+                                             &path,
+                                             removed,
+                                             prefix);
+            if difference.is_some() {
+                return difference;
+            }
+        } else if removed.contains(&path.as_path()) {
+            if new.is_some() {
+                return Some(path);
+            }
+        } else if old.map(|entry| (entry.id(), entry.filemode())) !=
+                  new.map(|entry| (entry.id(), entry.filemode())) {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+fn as_tree<'repo>(repo: &'repo Repository, entry: Option<TreeEntry>) -> Option<Tree<'repo>> {
+    entry.filter(|entry| entry.kind() == Some(ObjectType::Tree))
+        .map(|entry| {
+            repo.find_tree(entry.id())
+                .unwrap_or_else(|_| panic!("Couldn't find the tree {}", entry.id()))
+        })
+}
+
+// Lists the files in the tree, sorted by path. Reading a tree into an in-memory index doesn't write
+// anything into the repository.
+fn files(tree: &Tree) -> Vec<FileEntry> {
+    let mut index = Index::new().expect("Couldn't create an in-memory index");
+    index.read_tree(tree)
+        .unwrap_or_else(|_| panic!("Couldn't read the tree {} into index", tree.id()));
+    let mut files: Vec<FileEntry> = index.iter()
+        .map(|entry| (entry.path, entry.mode, entry.id))
+        .collect();
+    files.sort();
+    files
+}
+
+// Lists the files the filters make of the submodule's tree, with their new paths
+fn filtered_files(tree: &Tree, filter: &PathFilter) -> Vec<FileEntry> {
+    let mut files: Vec<FileEntry> = files(tree)
+        .into_iter()
+        .filter_map(|(path, mode, id)| filter.apply(&path).map(|path| (path, mode, id)))
+        .collect();
+    files.sort();
+    files
+}
+
+fn same_signature(a: &Signature, b: &Signature) -> bool {
+    a.name_bytes() == b.name_bytes() && a.email_bytes() == b.email_bytes() &&
+    a.when().seconds() == b.when().seconds() &&
+    a.when().offset_minutes() == b.when().offset_minutes()
+}
+
+fn list_ids<I: Iterator<Item = Oid>>(ids: I) -> String {
+    let ids: Vec<String> = ids.map(|id| id.to_string()).collect();
+    if ids.is_empty() {
+        String::from("none")
+    } else {
+        ids.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{IndexEntry, IndexTime};
+    use testutil::TempRepo;
+
+    // Writes a tree out of (path, contents) pairs; contents of None make a gitlink instead
+    fn tree<'r>(repo: &'r Repository, files: &[(&str, Option<&str>)]) -> Tree<'r> {
+        let mut index = Index::new().expect("Couldn't create an in-memory index");
+        for &(path, contents) in files {
+            let (id, mode) = match contents {
+                Some(contents) => {
+                    (repo.blob(contents.as_bytes()).expect("Couldn't write a blob"), 0o100644)
+                }
+                None => (Oid::from_bytes(&[1; 20]).expect("Couldn't create an ID"), 0o160000),
+            };
+            let entry = IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id,
+                flags: path.len() as u16,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            };
+            index.add(&entry).expect("Couldn't add an entry to the index");
+        }
+        let id = index.write_tree_to(repo).expect("Couldn't write the index into a tree");
+        repo.find_tree(id).expect("Couldn't find the tree we just wrote")
+    }
+
+    fn compare(repo: &Repository,
+               rewritten: &Tree,
+               submodule_tree: &Tree,
+               filter: &PathFilter)
+               -> Option<String> {
+        let original = tree(repo,
+                            &[("m", Some("m")),
+                              ("ext/sub", None),
+                              ("ext/readme", Some("r")),
+                              (".gitmodules", Some("g"))]);
+        compare_trees(repo,
+                      Some(&original),
+                      rewritten,
+                      &[Path::new("ext/sub"), Path::new(".gitmodules")],
+                      Path::new("lib/sub"),
+                      submodule_tree,
+                      &mut SubtreeFilter::new(filter))
+    }
+
+    #[test]
+    fn rewritten_tree_is_checked_against_the_submodule() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let submodule_tree = tree(repo, &[("a", Some("a")), ("doc/b", Some("b"))]);
+        let no_filter = PathFilter::new(&[], &[], Vec::new()).expect("Couldn't create a filter");
+
+        let good = tree(repo,
+                        &[("m", Some("m")),
+                          ("ext/readme", Some("r")),
+                          ("lib/sub/a", Some("a")),
+                          ("lib/sub/doc/b", Some("b"))]);
+        assert_eq!(compare(repo, &good, &submodule_tree, &no_filter), None);
+
+        let gitmodules_kept = tree(repo,
+                                   &[("m", Some("m")),
+                                     ("ext/readme", Some("r")),
+                                     ("lib/sub/a", Some("a")),
+                                     ("lib/sub/doc/b", Some("b")),
+                                     (".gitmodules", Some("g"))]);
+        assert!(compare(repo, &gitmodules_kept, &submodule_tree, &no_filter).is_some());
+
+        let file_lost = tree(repo,
+                             &[("m", Some("m")),
+                               ("lib/sub/a", Some("a")),
+                               ("lib/sub/doc/b", Some("b"))]);
+        assert!(compare(repo, &file_lost, &submodule_tree, &no_filter).is_some());
+
+        let submodule_changed = tree(repo,
+                                     &[("m", Some("m")),
+                                       ("ext/readme", Some("r")),
+                                       ("lib/sub/a", Some("changed")),
+                                       ("lib/sub/doc/b", Some("b"))]);
+        assert!(compare(repo, &submodule_changed, &submodule_tree, &no_filter).is_some());
+    }
+
+    #[test]
+    fn filters_are_applied_to_the_submodule() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let submodule_tree = tree(repo, &[("a", Some("a")), ("doc/b", Some("b"))]);
+        let filter = PathFilter::new(&["doc/*"], &[], vec![(String::from("doc"), String::new())])
+            .expect("Couldn't create a filter");
+
+        let good = tree(repo,
+                        &[("m", Some("m")), ("ext/readme", Some("r")), ("lib/sub/b", Some("b"))]);
+        assert_eq!(compare(repo, &good, &submodule_tree, &filter), None);
+
+        let unfiltered = tree(repo,
+                              &[("m", Some("m")),
+                                ("ext/readme", Some("r")),
+                                ("lib/sub/a", Some("a")),
+                                ("lib/sub/b", Some("b"))]);
+        assert!(compare(repo, &unfiltered, &submodule_tree, &filter).is_some());
+
+        // If the filters leave nothing, there should be no prefix directory at all
+        let nothing = PathFilter::new(&["nothing"], &[], Vec::new())
+            .expect("Couldn't create a filter");
+        let empty_prefix = tree(repo, &[("m", Some("m")), ("ext/readme", Some("r"))]);
+        assert_eq!(compare(repo, &empty_prefix, &submodule_tree, &nothing), None);
+        assert!(compare(repo, &good, &submodule_tree, &nothing).is_some());
+    }
+}
@@ -0,0 +1,46 @@
+use git2::{Repository, WorktreeLockStatus};
+
+// A working directory added with `git worktree add`. It shares the object database and the refs
+// with the main one, but has its own HEAD, index and checkout, all of which have to be updated
+// after the rewrite just like the main working directory.
+pub struct Worktree {
+    pub name: String,
+    pub repo: Repository,
+}
+
+// Opens all linked worktrees. The ones whose directories are gone (say, they're on a removable
+// drive that isn't mounted) can't be checked or updated, so they're skipped with a warning; their
+// HEADs are still moved to the rewritten history, though.
+pub fn open_linked(repo: &Repository) -> Vec<Worktree> {
+    let names = repo.worktrees().expect("Couldn't obtain the list of worktrees");
+
+    let mut result = Vec::new();
+    for name in names.iter().flatten() {
+        let worktree = repo.find_worktree(name)
+            .unwrap_or_else(|_| panic!("Couldn't find worktree `{}'", name));
+        if worktree.validate().is_err() {
+            let reason = match worktree.is_locked() {
+                Ok(WorktreeLockStatus::Locked(Some(reason))) => format!(" (locked: {})", reason),
+                _ => String::new(),
+            };
+            eprintln!("Skipping worktree `{}' at {}, as its directory is missing{}",
+                      name,
+                      worktree.path().display(),
+                      reason);
+            continue;
+        }
+
+        let worktree_repo = Repository::open_from_worktree(&worktree)
+            .unwrap_or_else(|_| panic!("Couldn't open worktree `{}'", name));
+        // A worktree with an orphan branch that has no commits yet has nothing to rewrite
+        if worktree_repo.head().is_err() {
+            continue;
+        }
+        result.push(Worktree {
+            name: String::from(name),
+            repo: worktree_repo,
+        });
+    }
+
+    result
+}